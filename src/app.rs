@@ -0,0 +1,366 @@
+//! Application bundles as input: `.app` directories, `.ipa` files and `.xcarchive` directories.
+
+extern crate failure;
+extern crate plist;
+extern crate zip;
+
+use self::failure::Error;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use error::RecompileError;
+use workdir::WorkDir;
+
+const INFO_PLIST: &str = "Info.plist";
+const BUNDLE_EXECUTABLE: &str = "CFBundleExecutable";
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().eq_ignore_ascii_case(extension))
+        .unwrap_or(false)
+}
+
+pub fn is_ipa(path: &Path) -> bool {
+    path.is_file() && has_extension(path, "ipa")
+}
+
+pub fn is_xcarchive(path: &Path) -> bool {
+    path.is_dir() && has_extension(path, "xcarchive")
+}
+
+pub fn is_app(path: &Path) -> bool {
+    path.is_dir() && has_extension(path, "app")
+}
+
+/// `CFBundleExecutable` of the bundle at `dir`, `Contents/Info.plist` for macOS bundles
+/// and `Resources/Info.plist` for macOS frameworks.
+fn bundle_executable(dir: &Path) -> Result<Option<PathBuf>, Error> {
+    let layouts = [
+        (dir.join("Contents").join(INFO_PLIST), dir.join("Contents").join("MacOS")),
+        (dir.join("Resources").join(INFO_PLIST), dir.to_path_buf()),
+        (dir.join(INFO_PLIST), dir.to_path_buf()),
+    ];
+    for (plist_path, exec_dir) in &layouts {
+        if !plist_path.is_file() {
+            continue;
+        }
+        let info = plist::Value::from_file(plist_path)
+            .map_err(|e| RecompileError::malformed("Info.plist", format!("{}, {}", plist_path.display(), e)))?;
+        let executable = info.as_dictionary()
+            .and_then(|dict| dict.get(BUNDLE_EXECUTABLE))
+            .and_then(|value| value.as_string());
+        return Ok(executable.map(|name| exec_dir.join(name)));
+    }
+
+    Ok(None)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// The main executable of the `.app` at `dir` followed by its embedded frameworks,
+/// dylibs, app extensions and watch apps.
+pub fn app_binaries(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let main = match bundle_executable(dir)? {
+        Some(main) => main,
+        None => bail!(RecompileError::malformed("app", format!("no {} in {}", BUNDLE_EXECUTABLE, dir.display()))),
+    };
+    let contents = if dir.join("Contents").is_dir() { dir.join("Contents") } else { dir.to_path_buf() };
+
+    let mut binaries = vec![main];
+    for path in sorted_entries(&contents.join("Frameworks"))? {
+        if has_extension(&path, "framework") {
+            // a framework without Info.plist is named after its binary
+            let executable = match bundle_executable(&path)? {
+                Some(executable) => executable,
+                None => path.join(path.file_stem().unwrap_or_default()),
+            };
+            if executable.is_file() {
+                binaries.push(executable);
+            }
+        } else if has_extension(&path, "dylib") {
+            binaries.push(path);
+        }
+    }
+    for nested in &["PlugIns", "Extensions", "Watch"] {
+        for path in sorted_entries(&contents.join(nested))? {
+            if has_extension(&path, "appex") || has_extension(&path, "app") {
+                binaries.append(&mut app_binaries(&path)?);
+            }
+        }
+    }
+
+    Ok(binaries)
+}
+
+fn find_app(dir: &Path) -> Result<PathBuf, Error> {
+    for path in sorted_entries(dir)? {
+        if is_app(&path) {
+            return Ok(path);
+        }
+    }
+    bail!(RecompileError::malformed("app", format!("no .app in {}", dir.display())))
+}
+
+/// An application opened for recompilation: where its `.app` is and which binaries it holds.
+pub struct AppInput {
+    /// the `.ipa`, `.xcarchive` or `.app` given on the command line
+    pub source: PathBuf,
+    pub app: PathBuf,
+    pub binaries: Vec<PathBuf>,
+    /// where an `.ipa` was unpacked, removed with the input
+    _unpacked: Option<WorkDir>,
+}
+
+impl AppInput {
+    pub fn open(path: &Path) -> Result<AppInput, Error> {
+        let (app, unpacked) = if is_ipa(path) {
+            let unpacked = WorkDir::new(None, false)?;
+            unpack_ipa(path, unpacked.path())?;
+            (find_app(&unpacked.join("Payload"))?, Some(unpacked))
+        } else if is_xcarchive(path) {
+            (find_app(&path.join("Products").join("Applications"))?, None)
+        } else {
+            (path.to_path_buf(), None)
+        };
+
+        Ok(AppInput {
+            source: path.to_path_buf(),
+            binaries: app_binaries(&app)?,
+            app,
+            _unpacked: unpacked,
+        })
+    }
+
+    /// Name of the application without extension.
+    pub fn name(&self) -> String {
+        self.app.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+    }
+
+    /// Write the app as `Payload/<name>.app` into a new `.ipa`, with every binary in
+    /// `replaced` swapped for its recompiled file.
+    pub fn write_ipa(&self, replaced: &HashMap<PathBuf, PathBuf>, output: &Path) -> Result<(), Error> {
+        let mut writer = zip::ZipWriter::new(fs::File::create(output)?);
+        let root = self.app.parent().unwrap_or_else(|| Path::new(""));
+        writer.add_directory("Payload/", zip::write::FileOptions::default())?;
+        add_tree(&mut writer, root, &self.app, replaced)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Path of `path` inside the zip: `Payload/` followed by the path relative to `root`.
+fn zip_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let parts = relative.components()
+        .map(|part| part.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    format!("Payload/{}", parts.join("/"))
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_: &fs::Metadata) -> u32 {
+    0o644
+}
+
+fn add_tree<W: Write + io::Seek>(writer: &mut zip::ZipWriter<W>, root: &Path, dir: &Path, replaced: &HashMap<PathBuf, PathBuf>) -> Result<(), Error> {
+    let options = zip::write::FileOptions::default();
+    writer.add_directory(format!("{}/", zip_name(root, dir)), options)?;
+
+    for path in sorted_entries(dir)? {
+        let meta = fs::symlink_metadata(&path)?;
+        let name = zip_name(root, &path);
+        if meta.file_type().is_symlink() {
+            writer.add_symlink(name, fs::read_link(&path)?.to_string_lossy(), options)?;
+        } else if meta.is_dir() {
+            add_tree(writer, root, &path, replaced)?;
+        } else {
+            let source = replaced.get(&path).map(PathBuf::as_path).unwrap_or(&path);
+            writer.start_file(name, options.unix_permissions(file_mode(&meta)))?;
+            io::copy(&mut fs::File::open(source)?, writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpack the zip at `ipa` into `dir`, keeping the permissions and symbolic links.
+pub fn unpack_ipa(ipa: &Path, dir: &Path) -> Result<(), Error> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(ipa)?)
+        .map_err(|e| RecompileError::malformed("ipa", format!("{}, {}", ipa.display(), e)))?;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = match entry.enclosed_name() {
+            Some(name) => name.to_path_buf(),
+            // an entry escaping the destination
+            None => bail!(RecompileError::malformed("ipa", format!("bad entry name {}", entry.name()))),
+        };
+        let path = no_symlink_ancestor(dir, &name)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mode = entry.unix_mode();
+        if mode.map(|mode| mode & 0o170_000 == 0o120_000).unwrap_or(false) {
+            let mut target = String::new();
+            io::Read::read_to_string(&mut entry, &mut target)?;
+            if !link_stays_inside(&name, &target) {
+                bail!(RecompileError::malformed("ipa", format!("symbolic link {} -> {} escapes the archive", name.display(), target)));
+            }
+            symlink(&target, &path)?;
+            continue;
+        }
+        io::copy(&mut entry, &mut fs::File::create(&path)?)?;
+        if let Some(mode) = mode {
+            set_mode(&path, mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `name` below `dir`, refusing to go through a symbolic link an earlier entry created
+/// or to write over one.
+fn no_symlink_ancestor(dir: &Path, name: &Path) -> Result<PathBuf, Error> {
+    let mut path = dir.to_path_buf();
+    for component in name.components() {
+        path.push(component);
+        let is_link = fs::symlink_metadata(&path).map(|meta| meta.file_type().is_symlink()).unwrap_or(false);
+        if is_link {
+            bail!(RecompileError::malformed("ipa", format!("entry {} goes through the symbolic link {}", name.display(), path.display())));
+        }
+    }
+    Ok(path)
+}
+
+/// Whether the relative `target` of the link `name` resolves inside the archive root.
+fn link_stays_inside(name: &Path, target: &str) -> bool {
+    let target = Path::new(target);
+    if target.has_root() {
+        return false;
+    }
+    let mut depth = name.components().count() as isize - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {},
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    fs::write(path, target)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    extern crate zip;
+
+    use self::zip::write::{FileOptions, ZipWriter};
+    use super::*;
+
+    use std::env;
+
+    /// An ipa of `(name, Some(link target) or None for a file)` entries.
+    fn ipa(root: &Path, entries: &[(&str, Option<&str>)]) -> PathBuf {
+        let path = root.join("App.ipa");
+        let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+        for &(name, target) in entries {
+            match target {
+                Some(target) => zip.add_symlink(name, target, FileOptions::default()).unwrap(),
+                None => {
+                    zip.start_file(name, FileOptions::default()).unwrap();
+                    zip.write_all(b"contents").unwrap();
+                },
+            }
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    fn unpack(test: &str, entries: &[(&str, Option<&str>)]) -> (PathBuf, Result<(), Error>) {
+        let root = env::temp_dir().join(format!("BitcodeRecompiler-ipa-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("out");
+        fs::create_dir_all(&dir).unwrap();
+        let result = unpack_ipa(&ipa(&root, entries), &dir);
+        (root, result)
+    }
+
+    #[test]
+    fn links_inside_the_bundle() {
+        let (root, result) = unpack("inside", &[
+            ("Payload/App.app/Frameworks/A.framework/Versions/A/A", None),
+            ("Payload/App.app/Frameworks/A.framework/A", Some("Versions/A/A")),
+            ("Payload/App.app/Frameworks/B", Some("../Info.plist")),
+        ]);
+        result.unwrap();
+        assert_eq!(fs::read(root.join("out/Payload/App.app/Frameworks/A.framework/A")).unwrap(), b"contents");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn refuses_escaping_links() {
+        for (test, target) in &[("absolute", "/etc"), ("parent", "../../../..")] {
+            let (root, result) = unpack(test, &[("Payload/link", Some(target))]);
+            assert!(result.unwrap_err().to_string().contains("escapes the archive"));
+            fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuses_writing_through_links() {
+        let (root, result) = unpack("through", &[
+            ("Payload/link", Some(".")),
+            ("Payload/link/file", None),
+        ]);
+        assert!(result.unwrap_err().to_string().contains("goes through the symbolic link"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+extern crate byteorder;
+extern crate failure;
+extern crate mach_object;
+
+use self::byteorder::{LittleEndian, WriteBytesExt};
+use self::failure::Error;
+use mach_object::{OFile, Symbol, SymbolReader};
+
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use error::RecompileError;
+use std::str;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_FMAG: &[u8] = b"`\n";
+const AR_EFMT1: &str = "#1/";
+const AR_HEADER_SIZE: usize = 60;
+
+const SYMDEF: &str = "__.SYMDEF";
+const SYMDEF_SORTED: &str = "__.SYMDEF SORTED";
+
+/// A member of a BSD `ar` archive, borrowing its contents from the archive.
+#[derive(Debug, Clone)]
+pub struct ArMember<'a> {
+    pub name: String,
+    pub date: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> ArMember<'a> {
+    pub fn is_symdef(&self) -> bool {
+        self.name == SYMDEF || self.name == SYMDEF_SORTED
+    }
+}
+
+fn header_field(header: &[u8], start: usize, len: usize) -> Result<&str, Error> {
+    Ok(str::from_utf8(&header[start..start + len])?.trim_end())
+}
+
+fn parse_number(field: &str, radix: u32) -> Result<u64, Error> {
+    if field.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(field, radix).map_err(|_| format_err!("bad ar header field \"{}\"", field))
+}
+
+/// Walk every member of the archive, including the symbol table.
+pub fn read_members(payload: &[u8]) -> Result<Vec<ArMember<'_>>, Error> {
+    if !payload.starts_with(AR_MAGIC) {
+        bail!(RecompileError::malformed("archive", "not an ar archive"));
+    }
+
+    let mut members = Vec::new();
+    let mut off = AR_MAGIC.len();
+    while off + AR_HEADER_SIZE <= payload.len() {
+        let header = &payload[off..off + AR_HEADER_SIZE];
+        if &header[58..60] != AR_FMAG {
+            bail!(RecompileError::malformed("archive", format!("bad ar member header at 0x{:x}", off)));
+        }
+
+        let mut name = header_field(header, 0, 16)?.to_string();
+        let date = parse_number(header_field(header, 16, 12)?, 10)?;
+        let uid = parse_number(header_field(header, 28, 6)?, 10)? as u32;
+        let gid = parse_number(header_field(header, 34, 6)?, 10)? as u32;
+        let mode = parse_number(header_field(header, 40, 8)?, 8)? as u32;
+        let size = parse_number(header_field(header, 48, 10)?, 10)? as usize;
+
+        let mut start = off + AR_HEADER_SIZE;
+        let end = start + size;
+        if end > payload.len() {
+            bail!(RecompileError::malformed("archive", format!("ar member {} exceeds the archive", name)));
+        }
+
+        if name.starts_with(AR_EFMT1) {
+            let len = parse_number(&name[AR_EFMT1.len()..], 10)? as usize;
+            if len > size {
+                bail!(RecompileError::malformed("archive", format!("bad ar extended name length {}", len)));
+            }
+            name = str::from_utf8(&payload[start..start + len])?
+                .trim_end_matches('\0')
+                .to_string();
+            start += len;
+        } else if name.ends_with('/') {
+            name.pop();
+        }
+
+        members.push(ArMember {
+            name,
+            date,
+            uid,
+            gid,
+            mode,
+            data: &payload[start..end],
+        });
+
+        off = end + (end & 1);
+    }
+
+    Ok(members)
+}
+
+/// External symbols defined by a Mach-O object, as listed in the archive table of contents.
+fn defined_symbols(data: &[u8]) -> Result<Vec<String>, Error> {
+    let ofile = OFile::parse(&mut Cursor::new(data))?;
+    let mut cur = Cursor::new(data);
+    let mut names = Vec::new();
+
+    if let Some(symbols) = ofile.symbols(&mut cur) {
+        for symbol in symbols {
+            match symbol {
+                Symbol::Defined { name: Some(name), external: true, .. }
+                | Symbol::Absolute { name: Some(name), external: true, .. } => {
+                    names.push(name.to_string());
+                },
+                _ => {},
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+// the extended name is padded so the member contents stay 8 byte aligned
+fn name_len(name: &str) -> usize {
+    let mut len = name.len() + 1;
+    while !(AR_HEADER_SIZE + len).is_multiple_of(8) {
+        len += 1;
+    }
+    len
+}
+
+fn member_size(name: &str, data_len: usize) -> usize {
+    let size = AR_HEADER_SIZE + name_len(name) + data_len;
+    size + (size & 1)
+}
+
+fn write_header(w: &mut Vec<u8>, member: &ArMember) -> Result<(), Error> {
+    let name = member.name.as_str();
+    let name_len = name_len(name);
+
+    write!(w, "{:<16}", format!("{}{}", AR_EFMT1, name_len))?;
+    write!(w, "{:<12}", member.date)?;
+    write!(w, "{:<6}", member.uid)?;
+    write!(w, "{:<6}", member.gid)?;
+    write!(w, "{:<8o}", member.mode)?;
+    write!(w, "{:<10}", name_len + member.data.len())?;
+    w.write_all(AR_FMAG)?;
+    w.write_all(name.as_bytes())?;
+    w.resize(w.len() + name_len - name.len(), 0);
+
+    Ok(())
+}
+
+/// Write a BSD archive of `members` preceded by a fresh `__.SYMDEF SORTED` table of contents.
+pub fn write_archive(members: &[ArMember], output: &Path) -> Result<(), Error> {
+    let members = members.iter().filter(|member| !member.is_symdef()).collect::<Vec<_>>();
+
+    let mut symbols = Vec::new();
+    for (index, member) in members.iter().enumerate() {
+        // members that are not Mach-O objects simply do not contribute symbols
+        if let Ok(names) = defined_symbols(member.data) {
+            for name in names {
+                symbols.push((name, index));
+            }
+        }
+    }
+    symbols.sort();
+    symbols.dedup_by(|a, b| a.0 == b.0);
+
+    let mut strtab = Vec::new();
+    let mut strx = Vec::with_capacity(symbols.len());
+    for (name, _) in &symbols {
+        strx.push(strtab.len() as u32);
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    while !strtab.len().is_multiple_of(8) {
+        strtab.push(0);
+    }
+
+    let symdef_data_len = 4 + symbols.len() * 8 + 4 + strtab.len();
+    let symdef_size = member_size(SYMDEF_SORTED, symdef_data_len);
+
+    let mut offsets = Vec::with_capacity(members.len());
+    let mut off = AR_MAGIC.len() + symdef_size;
+    for member in &members {
+        offsets.push(off as u32);
+        off += member_size(&member.name, member.data.len());
+    }
+
+    let mut symdef = Vec::with_capacity(symdef_data_len);
+    symdef.write_u32::<LittleEndian>((symbols.len() * 8) as u32)?;
+    for (&(_, index), &strx) in symbols.iter().zip(strx.iter()) {
+        symdef.write_u32::<LittleEndian>(strx)?;
+        symdef.write_u32::<LittleEndian>(offsets[index])?;
+    }
+    symdef.write_u32::<LittleEndian>(strtab.len() as u32)?;
+    symdef.write_all(&strtab)?;
+
+    let symdef_member = ArMember {
+        name: SYMDEF_SORTED.to_string(),
+        date: members.first().map(|member| member.date).unwrap_or(0),
+        uid: members.first().map(|member| member.uid).unwrap_or(0),
+        gid: members.first().map(|member| member.gid).unwrap_or(0),
+        mode: 0o100644,
+        data: &symdef,
+    };
+
+    let mut w = Vec::with_capacity(off);
+    w.write_all(AR_MAGIC)?;
+    for member in Some(&symdef_member).into_iter().chain(members) {
+        write_header(&mut w, member)?;
+        w.write_all(member.data)?;
+        if w.len() & 1 != 0 {
+            w.push(b'\n');
+        }
+    }
+
+    fs::write(output, w)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate byteorder;
+
+    use self::byteorder::ByteOrder;
+    use super::*;
+
+    use std::env;
+
+    /// An `MH_OBJECT` whose symbol table defines the external absolute `symbol`.
+    fn object(symbol: &str) -> Vec<u8> {
+        let symoff = 32 + 24;
+        let stroff = symoff + 16;
+        let mut strtab = vec![0];
+        strtab.extend_from_slice(symbol.as_bytes());
+        strtab.push(0);
+
+        let mut w = Vec::new();
+        for value in &[0xfeed_facf, 0x0100_000c, 0, 1, 1, 24, 0, 0] {
+            w.write_u32::<LittleEndian>(*value).unwrap();
+        }
+        // LC_SYMTAB
+        for value in &[2, 24, symoff, 1, stroff, strtab.len() as u32] {
+            w.write_u32::<LittleEndian>(*value).unwrap();
+        }
+        w.write_u32::<LittleEndian>(1).unwrap();
+        w.push(0x03); // N_ABS | N_EXT
+        w.push(0);
+        w.write_u16::<LittleEndian>(0).unwrap();
+        w.write_u64::<LittleEndian>(0x1000).unwrap();
+        w.extend_from_slice(&strtab);
+        w
+    }
+
+    fn member<'a>(name: &str, data: &'a [u8]) -> ArMember<'a> {
+        ArMember {
+            name: name.to_string(),
+            date: 1_500_000_000,
+            uid: 501,
+            gid: 20,
+            mode: 0o100644,
+            data,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let foo = object("_foo");
+        let bar = object("_bar");
+        let odd = b"not an object".to_vec();
+        let stale_symdef = b"stale".to_vec();
+        let long_name = "a_member_name_longer_than_sixteen_bytes.o";
+        let members = vec![
+            member(SYMDEF_SORTED, &stale_symdef),
+            member("foo.o", &foo),
+            member("notes.txt", &odd),
+            member(long_name, &bar),
+        ];
+
+        let path = env::temp_dir().join(format!("BitcodeRecompiler-ar-{}.a", std::process::id()));
+        write_archive(&members, &path).unwrap();
+        let payload = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let read = read_members(&payload).unwrap();
+        let names = read.iter().map(|member| member.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![SYMDEF_SORTED, "foo.o", "notes.txt", long_name]);
+        for (written, read) in members[1..].iter().zip(read[1..].iter()) {
+            assert_eq!(read.data, written.data, "{}", read.name);
+            assert_eq!((read.date, read.uid, read.gid, read.mode), (written.date, written.uid, written.gid, written.mode));
+        }
+
+        // the table of contents is sorted by name and points at the member headers
+        let symdef = read[0].data;
+        let count = LittleEndian::read_u32(symdef) as usize / 8;
+        let strtab = &symdef[4 + count * 8 + 4..];
+        let mut entries = Vec::new();
+        for index in 0..count {
+            let strx = LittleEndian::read_u32(&symdef[4 + index * 8..]) as usize;
+            let offset = LittleEndian::read_u32(&symdef[8 + index * 8..]) as usize;
+            let name = str::from_utf8(strtab[strx..].split(|c| *c == 0).next().unwrap()).unwrap();
+            let header_name = header_field(&payload[offset..], 0, 16).unwrap().to_string();
+            entries.push((name.to_string(), header_name));
+        }
+        assert_eq!(entries, vec![
+            ("_bar".to_string(), format!("#1/{}", name_len(long_name))),
+            ("_foo".to_string(), format!("#1/{}", name_len("foo.o"))),
+        ]);
+    }
+
+    #[test]
+    fn short_names() {
+        let mut payload = AR_MAGIC.to_vec();
+        write!(payload, "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}", "a.o/", 0, 0, 0, 644, 3).unwrap();
+        payload.extend_from_slice(AR_FMAG);
+        payload.extend_from_slice(b"abc\n");
+
+        let members = read_members(&payload).unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].name, "a.o");
+        assert_eq!(members[0].data, b"abc");
+        assert_eq!(members[0].mode, 0o644);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(read_members(b"!<arch\n").is_err());
+
+        let mut payload = AR_MAGIC.to_vec();
+        write!(payload, "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}", "a.o", 0, 0, 0, 644, 100).unwrap();
+        payload.extend_from_slice(AR_FMAG);
+        assert!(read_members(&payload).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+extern crate failure;
+
+use self::failure::Error;
+
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const MH_MAGIC: u32 = 0xfeed_face;
+const MH_CIGAM: u32 = 0xcefa_edfe;
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const MH_CIGAM_64: u32 = 0xcffa_edfe;
+const FAT_MAGIC: u32 = 0xcafe_babe;
+
+/// Whether `path` starts like a thin or fat Mach-O file.
+pub fn is_macho(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    let read = fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic));
+    if read.is_err() {
+        return false;
+    }
+    matches!(u32::from_be_bytes(magic), MH_MAGIC | MH_CIGAM | MH_MAGIC_64 | MH_CIGAM_64 | FAT_MAGIC)
+}
+
+fn scan(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    // the same order on every file system
+    entries.sort();
+
+    for path in entries {
+        // symbolic links inside bundles point back into the bundle, following them
+        // would only find the same binaries again
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            scan(&path, files)?;
+        } else if meta.is_file() && is_macho(&path) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Expand the inputs of a batch: files are kept as they are, directories are
+/// searched recursively for Mach-O files.
+pub fn collect_inputs<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let count = files.len();
+            scan(path, &mut files)?;
+            if files.len() == count {
+                bail!("no Mach-O file in {}", path.display());
+            }
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    Ok(files)
+}
+
+/// How one binary of a batch went.
+#[derive(Debug, Clone)]
+pub struct BatchEntry {
+    pub file: PathBuf,
+    /// the error message, `None` on success
+    pub error: Option<String>,
+}
+
+/// The outcome of every binary of a batch, in the order they were processed.
+#[derive(Debug, Clone, Default)]
+pub struct BatchSummary {
+    pub entries: Vec<BatchEntry>,
+}
+
+impl BatchSummary {
+    pub fn new() -> BatchSummary {
+        BatchSummary::default()
+    }
+
+    pub fn push(&mut self, file: &Path, result: &Result<(), Error>) {
+        self.entries.push(BatchEntry {
+            file: file.to_path_buf(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+
+    pub fn failed(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.error.is_some()).count()
+    }
+}
+
+impl fmt::Display for BatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} binaries, {} succeeded, {} failed", self.entries.len(), self.entries.len() - self.failed(), self.failed())?;
+        for entry in &self.entries {
+            match entry.error {
+                // only the first line, a failed link carries the whole linker log
+                Some(ref error) => writeln!(f, "  FAILED  {}: {}", entry.file.display(), error.lines().next().unwrap_or_default())?,
+                None => writeln!(f, "  ok      {}", entry.file.display())?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,557 @@
+//! Just enough of the LLVM bitstream to tell which compiler produced a module.
+
+extern crate byteorder;
+extern crate failure;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+use self::failure::Error;
+
+use error::RecompileError;
+
+pub const BITCODE_MAGIC: &[u8] = b"BC\xc0\xde";
+pub const BITCODE_WRAPPER_MAGIC: u32 = 0x0b17_c0de;
+
+const WRAPPER_HEADER_SIZE: usize = 20;
+
+/// Abbreviation ids every block knows.
+const END_BLOCK: u64 = 0;
+const ENTER_SUBBLOCK: u64 = 1;
+const DEFINE_ABBREV: u64 = 2;
+const UNABBREV_RECORD: u64 = 3;
+
+const IDENTIFICATION_BLOCK_ID: u64 = 13;
+const IDENTIFICATION_CODE_STRING: u64 = 1;
+const IDENTIFICATION_CODE_EPOCH: u64 = 2;
+
+const CHAR6: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789._";
+
+/// Whether `data` starts with raw or wrapped bitcode.
+pub fn is_bitcode(data: &[u8]) -> bool {
+    data.starts_with(BITCODE_MAGIC) || (data.len() >= 4 && LittleEndian::read_u32(data) == BITCODE_WRAPPER_MAGIC)
+}
+
+/// The header Darwin tools put in front of bitcode.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct WrapperHeader {
+    pub version: u32,
+    /// where the bitcode starts, from the beginning of the wrapper
+    pub offset: u32,
+    pub size: u32,
+    pub cputype: u32,
+}
+
+impl WrapperHeader {
+    pub fn parse(data: &[u8]) -> Option<WrapperHeader> {
+        if data.len() < WRAPPER_HEADER_SIZE || LittleEndian::read_u32(data) != BITCODE_WRAPPER_MAGIC {
+            return None;
+        }
+        Some(WrapperHeader {
+            version: LittleEndian::read_u32(&data[4..]),
+            offset: LittleEndian::read_u32(&data[8..]),
+            size: LittleEndian::read_u32(&data[12..]),
+            cputype: LittleEndian::read_u32(&data[16..]),
+        })
+    }
+}
+
+/// What the header and `IDENTIFICATION` block of a module tell about it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModuleHeader {
+    /// `None` for raw bitcode
+    pub wrapper: Option<WrapperHeader>,
+    /// e.g. `APPLE_1_902.0.39.1_0` or `LLVM14.0.6`, `None` for bitcode older than LLVM 3.8
+    pub producer: Option<String>,
+    pub epoch: Option<u64>,
+}
+
+impl ModuleHeader {
+    pub fn parse(data: &[u8]) -> Result<ModuleHeader, Error> {
+        let wrapper = WrapperHeader::parse(data);
+        let bitcode = match wrapper {
+            Some(wrapper) => {
+                let start = wrapper.offset as usize;
+                match start.checked_add(wrapper.size as usize) {
+                    Some(end) if end <= data.len() => &data[start..end],
+                    _ => bail!(RecompileError::malformed("bitcode", "the wrapper exceeds the module")),
+                }
+            },
+            None => data,
+        };
+        if !bitcode.starts_with(BITCODE_MAGIC) {
+            bail!(RecompileError::malformed("bitcode", "no bitcode magic"));
+        }
+
+        let mut reader = BitReader::new(bitcode);
+        reader.pos = 32;
+        // bitcode without identification says nothing of its producer
+        let (producer, epoch) = read_identification(&mut reader).unwrap_or_default();
+
+        Ok(ModuleHeader {
+            wrapper,
+            producer,
+            epoch,
+        })
+    }
+}
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    /// `width` bits as a fixed width field, `None` past the end or for a width the
+    /// bitstream cannot have.
+    pub fn read(&mut self, width: usize) -> Option<u64> {
+        if width == 0 || width > 64 {
+            return None;
+        }
+        let mut value = 0;
+        for i in 0..width {
+            let byte = *self.data.get((self.pos + i) / 8)?;
+            value |= u64::from((byte >> ((self.pos + i) % 8)) & 1) << i;
+        }
+        self.pos += width;
+        Some(value)
+    }
+
+    /// A variable width field of `width` bit chunks, `None` once it overflows 64 bits.
+    pub fn read_vbr(&mut self, width: usize) -> Option<u64> {
+        if width == 0 || width > 64 {
+            return None;
+        }
+        let hi = 1 << (width - 1);
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            if shift >= 64 {
+                return None;
+            }
+            let piece = self.read(width)?;
+            value |= (piece & (hi - 1)) << shift;
+            if piece & hi == 0 {
+                return Some(value);
+            }
+            shift += width - 1;
+        }
+    }
+
+    fn align32(&mut self) {
+        self.pos = self.pos.div_ceil(32) * 32;
+    }
+
+    /// Skip a block whose `ENTER_SUBBLOCK` id and block id were read.
+    fn skip_block(&mut self) -> Option<()> {
+        self.read_vbr(4)?;
+        self.align32();
+        let words = self.read(32)? as usize;
+        self.pos += words * 32;
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AbbrevOp {
+    Literal(u64),
+    Fixed(usize),
+    Vbr(usize),
+    Array,
+    Char6,
+    Blob,
+}
+
+fn read_abbrev(reader: &mut BitReader) -> Option<Vec<AbbrevOp>> {
+    let count = reader.read_vbr(5)?;
+    let mut ops = Vec::new();
+    for _ in 0..count {
+        let op = if reader.read(1)? == 1 {
+            AbbrevOp::Literal(reader.read_vbr(8)?)
+        } else {
+            match reader.read(3)? {
+                1 => AbbrevOp::Fixed(reader.read_vbr(5)? as usize),
+                2 => AbbrevOp::Vbr(reader.read_vbr(5)? as usize),
+                3 => AbbrevOp::Array,
+                4 => AbbrevOp::Char6,
+                5 => AbbrevOp::Blob,
+                _ => return None,
+            }
+        };
+        ops.push(op);
+    }
+    Some(ops)
+}
+
+fn read_scalar(reader: &mut BitReader, op: AbbrevOp) -> Option<u64> {
+    match op {
+        AbbrevOp::Literal(value) => Some(value),
+        AbbrevOp::Fixed(width) => reader.read(width),
+        AbbrevOp::Vbr(width) => reader.read_vbr(width),
+        AbbrevOp::Char6 => {
+            let value = reader.read(6)?;
+            Some(u64::from(CHAR6[value as usize]))
+        },
+        AbbrevOp::Array | AbbrevOp::Blob => None,
+    }
+}
+
+/// Code and operands of a record written with `ops`.
+fn read_abbreviated(reader: &mut BitReader, ops: &[AbbrevOp]) -> Option<(u64, Vec<u64>)> {
+    let mut values = Vec::new();
+    let mut index = 0;
+    while index < ops.len() {
+        match ops[index] {
+            AbbrevOp::Array => {
+                // the element encoding is the last operand
+                let element = *ops.get(index + 1)?;
+                let len = reader.read_vbr(6)?;
+                for _ in 0..len {
+                    values.push(read_scalar(reader, element)?);
+                }
+                index += 2;
+            },
+            AbbrevOp::Blob => {
+                let len = reader.read_vbr(6)? as usize;
+                reader.align32();
+                for _ in 0..len {
+                    values.push(reader.read(8)?);
+                }
+                reader.align32();
+                index += 1;
+            },
+            op => {
+                values.push(read_scalar(reader, op)?);
+                index += 1;
+            },
+        }
+    }
+    if values.is_empty() {
+        return None;
+    }
+    let code = values.remove(0);
+    Some((code, values))
+}
+
+/// Producer and epoch from the `IDENTIFICATION` block, when it is the first block of the stream.
+fn read_identification(reader: &mut BitReader) -> Option<(Option<String>, Option<u64>)> {
+    if reader.read(2)? != ENTER_SUBBLOCK || reader.read_vbr(8)? != IDENTIFICATION_BLOCK_ID {
+        return None;
+    }
+    let width = reader.read_vbr(4)? as usize;
+    reader.align32();
+    reader.read(32)?;
+
+    let mut abbrevs = Vec::new();
+    let mut producer = None;
+    let mut epoch = None;
+    loop {
+        let (code, values) = match reader.read(width)? {
+            END_BLOCK => break,
+            ENTER_SUBBLOCK => {
+                reader.read_vbr(8)?;
+                reader.skip_block()?;
+                continue;
+            },
+            DEFINE_ABBREV => {
+                abbrevs.push(read_abbrev(reader)?);
+                continue;
+            },
+            UNABBREV_RECORD => {
+                let code = reader.read_vbr(6)?;
+                let count = reader.read_vbr(6)?;
+                let values = (0..count).map(|_| reader.read_vbr(6)).collect::<Option<Vec<_>>>()?;
+                (code, values)
+            },
+            id => {
+                let ops = abbrevs.get(id as usize - 4)?.clone();
+                read_abbreviated(reader, &ops)?
+            },
+        };
+        match code {
+            IDENTIFICATION_CODE_STRING => {
+                producer = Some(values.iter().map(|c| *c as u8 as char).collect());
+            },
+            IDENTIFICATION_CODE_EPOCH => epoch = values.first().cloned(),
+            _ => {},
+        }
+    }
+
+    Some((producer, epoch))
+}
+
+/// Length of the bitcode module at the start of `data`, found by skipping its top level blocks.
+fn module_len(data: &[u8]) -> Option<usize> {
+    if let Some(wrapper) = WrapperHeader::parse(data) {
+        return Some(wrapper.offset as usize + wrapper.size as usize).filter(|len| *len <= data.len());
+    }
+    if !data.starts_with(BITCODE_MAGIC) {
+        return None;
+    }
+
+    let mut reader = BitReader::new(data);
+    reader.pos = 32;
+    loop {
+        let block_start = reader.pos;
+        // ENTER_SUBBLOCK with the initial 2 bit abbreviation width
+        if reader.read(2) != Some(ENTER_SUBBLOCK) {
+            return Some(block_start / 8);
+        }
+        reader.read_vbr(8)?;
+        reader.skip_block()?;
+        if reader.pos / 8 >= data.len() {
+            return Some(data.len().min(reader.pos / 8));
+        }
+    }
+}
+
+/// Split a section concatenating the module of every object, such as `.llvmbc` of a linked ELF file.
+pub fn split_modules(data: &[u8]) -> Vec<&[u8]> {
+    let mut modules = Vec::new();
+    let mut off = 0;
+    while off < data.len() {
+        // the linker may pad between input sections
+        if data[off] == 0 {
+            off += 1;
+            continue;
+        }
+        match module_len(&data[off..]) {
+            Some(len) if len > 0 => {
+                modules.push(&data[off..off + len]);
+                off += len;
+            },
+            _ => break,
+        }
+    }
+    modules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The writing side of `BitReader`, least significant bit first.
+    struct BitWriter {
+        bits: Vec<bool>,
+    }
+
+    impl BitWriter {
+        fn new() -> BitWriter {
+            let mut writer = BitWriter { bits: Vec::new() };
+            for byte in BITCODE_MAGIC {
+                writer.fixed(u64::from(*byte), 8);
+            }
+            writer
+        }
+
+        fn fixed(&mut self, value: u64, width: usize) {
+            self.bits.extend((0..width).map(|i| value >> i & 1 == 1));
+        }
+
+        fn vbr(&mut self, mut value: u64, width: usize) {
+            let hi = 1 << (width - 1);
+            while value >= hi {
+                self.fixed(value & (hi - 1) | hi, width);
+                value >>= width - 1;
+            }
+            self.fixed(value, width);
+        }
+
+        fn align32(&mut self) {
+            while !self.bits.len().is_multiple_of(32) {
+                self.bits.push(false);
+            }
+        }
+
+        /// A top level block `block_id` whose contents, in abbreviation ids of `width`
+        /// bits, are written by `contents`.
+        fn block<F: FnOnce(&mut BitWriter)>(&mut self, block_id: u64, width: usize, contents: F) {
+            self.fixed(ENTER_SUBBLOCK, 2);
+            self.vbr(block_id, 8);
+            self.vbr(width as u64, 4);
+            self.align32();
+            let size_at = self.bits.len();
+            self.fixed(0, 32);
+            contents(self);
+            self.fixed(END_BLOCK, width);
+            self.align32();
+            let words = ((self.bits.len() - size_at) / 32 - 1) as u64;
+            for i in 0..32 {
+                self.bits[size_at + i] = words >> i & 1 == 1;
+            }
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            self.bits.chunks(8)
+                .map(|byte| byte.iter().enumerate().fold(0, |acc, (i, bit)| acc | (u8::from(*bit) << i)))
+                .collect()
+        }
+    }
+
+    /// A module of an empty `MODULE` block, with `words` words of unread records.
+    fn module(words: usize) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.block(8, 3, |writer| {
+            for _ in 0..words {
+                writer.fixed(UNABBREV_RECORD, 3);
+                writer.vbr(1, 6);
+                writer.vbr(0, 6);
+                writer.align32();
+            }
+        });
+        writer.bytes()
+    }
+
+    fn wrapped(module: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in &[BITCODE_WRAPPER_MAGIC, 0, WRAPPER_HEADER_SIZE as u32, module.len() as u32, 0x0100_000c] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(module);
+        data
+    }
+
+    /// An `IDENTIFICATION` block followed by an empty `MODULE` block, the records written
+    /// as LLVM does with abbreviations or unabbreviated.
+    fn identified(producer: &str, epoch: u64, abbreviated: bool) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.block(IDENTIFICATION_BLOCK_ID, 5, |writer| {
+            if abbreviated {
+                // [STRING, array of char6]
+                writer.fixed(DEFINE_ABBREV, 5);
+                writer.vbr(3, 5);
+                writer.fixed(1, 1);
+                writer.vbr(IDENTIFICATION_CODE_STRING, 8);
+                writer.fixed(0, 1);
+                writer.fixed(3, 3);
+                writer.fixed(0, 1);
+                writer.fixed(4, 3);
+                // [EPOCH, vbr6]
+                writer.fixed(DEFINE_ABBREV, 5);
+                writer.vbr(2, 5);
+                writer.fixed(1, 1);
+                writer.vbr(IDENTIFICATION_CODE_EPOCH, 8);
+                writer.fixed(0, 1);
+                writer.fixed(2, 3);
+                writer.vbr(6, 5);
+
+                writer.fixed(4, 5);
+                writer.vbr(producer.len() as u64, 6);
+                for c in producer.bytes() {
+                    writer.fixed(CHAR6.iter().position(|x| *x == c).unwrap() as u64, 6);
+                }
+                writer.fixed(5, 5);
+                writer.vbr(epoch, 6);
+            } else {
+                writer.fixed(UNABBREV_RECORD, 5);
+                writer.vbr(IDENTIFICATION_CODE_STRING, 6);
+                writer.vbr(producer.len() as u64, 6);
+                for c in producer.bytes() {
+                    writer.vbr(u64::from(c), 6);
+                }
+                writer.fixed(UNABBREV_RECORD, 5);
+                writer.vbr(IDENTIFICATION_CODE_EPOCH, 6);
+                writer.vbr(1, 6);
+                writer.vbr(epoch, 6);
+            }
+        });
+        writer.block(8, 3, |_| {});
+        writer.bytes()
+    }
+
+    #[test]
+    fn identification() {
+        for abbreviated in &[false, true] {
+            for producer in &["LLVM14.0.6", "APPLE_1_902.0.39.1_0"] {
+                let header = ModuleHeader::parse(&identified(producer, 0, *abbreviated)).unwrap();
+                assert_eq!(header.wrapper, None);
+                assert_eq!(header.producer.as_deref(), Some(*producer));
+                assert_eq!(header.epoch, Some(0));
+            }
+        }
+    }
+
+    #[test]
+    fn identification_of_a_wrapped_module() {
+        let data = wrapped(&identified("APPLE_1_1403.0.22.14.1_0", 7, true));
+        let header = ModuleHeader::parse(&data).unwrap();
+        let wrapper = header.wrapper.unwrap();
+        assert_eq!((wrapper.offset, wrapper.cputype), (WRAPPER_HEADER_SIZE as u32, 0x0100_000c));
+        assert_eq!(header.producer.as_deref(), Some("APPLE_1_1403.0.22.14.1_0"));
+        assert_eq!(header.epoch, Some(7));
+    }
+
+    #[test]
+    fn module_without_identification() {
+        let header = ModuleHeader::parse(&module(1)).unwrap();
+        assert_eq!((header.producer, header.epoch), (None, None));
+
+        // a truncated identification block says nothing either
+        let data = identified("LLVM14.0.6", 0, false);
+        let header = ModuleHeader::parse(&data[..12]).unwrap();
+        assert_eq!(header.producer, None);
+    }
+
+    #[test]
+    fn malformed_modules() {
+        assert!(ModuleHeader::parse(b"not bitcode").is_err());
+        let mut data = wrapped(&module(1));
+        data.truncate(data.len() - 1);
+        assert!(ModuleHeader::parse(&data).is_err());
+    }
+
+    #[test]
+    fn split_concatenated_modules() {
+        let first = module(1);
+        let second = module(3);
+        let third = wrapped(&module(2));
+        let mut section = first.clone();
+        section.extend_from_slice(&second);
+        // the padding a linker puts between input sections
+        section.extend_from_slice(&[0; 4]);
+        section.extend_from_slice(&third);
+
+        let modules = split_modules(&section);
+        assert_eq!(modules, vec![&first[..], &second[..], &third[..]]);
+        assert!(modules.iter().all(|module| is_bitcode(module)));
+    }
+
+    #[test]
+    fn split_stops_at_garbage() {
+        let first = module(1);
+        let mut section = first.clone();
+        section.extend_from_slice(b"garbage");
+        assert_eq!(split_modules(&section), vec![&first[..]]);
+        assert!(split_modules(b"").is_empty());
+    }
+
+    #[test]
+    fn read_fields() {
+        // 0b0110 then the vbr3 chunks 0b111 (0b11, continued) and 0b001
+        let mut reader = BitReader::new(&[0b1111_0110, 0b0000_0000]);
+        assert_eq!(reader.read(4), Some(0b0110));
+        assert_eq!(reader.read_vbr(3), Some(0b11 | 0b01 << 2));
+        assert_eq!(reader.pos, 10);
+        assert_eq!(reader.read(7), None);
+    }
+
+    #[test]
+    fn read_rejects_bad_widths() {
+        let data = [0xff; 16];
+        for width in &[0, 65, usize::MAX] {
+            assert_eq!(BitReader::new(&data).read(*width), None);
+            assert_eq!(BitReader::new(&data).read_vbr(*width), None);
+        }
+        assert_eq!(BitReader::new(&data).read(64), Some(u64::MAX));
+    }
+
+    #[test]
+    fn read_vbr_stops_at_64_bits() {
+        // every chunk continues, the value would need more than 64 bits
+        let mut reader = BitReader::new(&[0xff; 32]);
+        assert_eq!(reader.read_vbr(8), None);
+        assert!(reader.pos <= 80);
+    }
+}
@@ -0,0 +1,539 @@
+//! Compare a recompiled binary with the original one, slice by slice.
+
+extern crate byteorder;
+extern crate failure;
+extern crate mach_object;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+use self::failure::Error;
+use mach_object::{BuildTarget, LoadCommand, MachCommand, OFile, Symbol, SymbolReader};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Cursor;
+
+use elf;
+use error::RecompileError;
+use fat;
+use manifest::Dylib;
+use pipeline::SEGMENT_NAME;
+use platform;
+
+/// `mach_object` leaves `LC_BUILD_VERSION` as a raw command.
+const LC_BUILD_VERSION: u32 = 0x32;
+
+/// Number of symbol names printed per difference, the JSON report has all of them.
+const LISTED_ITEMS: usize = 10;
+
+/// A thin Mach-O file on its own or as a slice of a fat file.
+pub struct ThinFile<'a> {
+    pub arch: String,
+    pub ofile: &'a OFile,
+    /// the bytes of the slice, offsets of the file are relative to them
+    pub payload: &'a [u8],
+}
+
+/// Every thin Mach-O file of `ofile`.
+pub fn thin_files<'a>(ofile: &'a OFile, payload: &'a [u8]) -> Result<Vec<ThinFile<'a>>, Error> {
+    let mut files = Vec::new();
+
+    match *ofile {
+        OFile::MachFile { ref header, .. } => {
+            files.push(ThinFile {
+                arch: fat::cpu_name(header.cputype, header.cpusubtype),
+                ofile,
+                payload,
+            });
+        },
+        OFile::FatFile { files: ref slices, .. } => {
+            for (arch, file) in slices {
+                let start = arch.offset as usize;
+                let end = start + arch.size as usize;
+                if end > payload.len() {
+                    bail!(RecompileError::malformed("fat file", format!("slice {} exceeds the file", fat::arch_name(arch))));
+                }
+                files.append(&mut thin_files(file, &payload[start..end])?);
+            }
+        },
+        _ => {},
+    }
+
+    Ok(files)
+}
+
+/// The platform and version an `LC_VERSION_MIN_*` or `LC_BUILD_VERSION` command asks for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MinVersion {
+    pub platform: String,
+    pub version: String,
+    /// `xxxx.yy.zz` packed as in the load command, for ordering
+    #[serde(skip)]
+    pub packed: u32,
+}
+
+impl MinVersion {
+    fn new(platform: &str, packed: u32) -> MinVersion {
+        let (major, minor, patch) = (packed >> 16, (packed >> 8) & 0xff, packed & 0xff);
+        let version = if patch == 0 {
+            format!("{}.{}", major, minor)
+        } else {
+            format!("{}.{}.{}", major, minor, patch)
+        };
+
+        MinVersion {
+            platform: platform.to_string(),
+            version,
+            packed,
+        }
+    }
+}
+
+impl fmt::Display for MinVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.platform, self.version)
+    }
+}
+
+fn build_platform(build_platform: u32) -> String {
+    match platform::from_build_platform(build_platform) {
+        Some(platform) => platform.ld_platform.to_string(),
+        None => format!("platform {}", build_platform),
+    }
+}
+
+fn command_name(command: &LoadCommand) -> String {
+    match *command {
+        LoadCommand::Command { cmd: LC_BUILD_VERSION, .. } => "LC_BUILD_VERSION".to_string(),
+        LoadCommand::Command { cmd, .. } => format!("LC_0x{:x}", cmd),
+        _ => command.name().to_string(),
+    }
+}
+
+/// What of one thin Mach-O file matters to the loader and to whoever links against it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Layout {
+    pub arch: String,
+    pub filetype: u32,
+    /// number of load commands by kind
+    pub load_commands: BTreeMap<String, usize>,
+    /// virtual size of every segment
+    pub segments: BTreeMap<String, u64>,
+    /// size of every section, keyed `segment,section`
+    pub sections: BTreeMap<String, u64>,
+    pub exports: BTreeSet<String>,
+    pub undefined: BTreeSet<String>,
+    pub dylibs: Vec<Dylib>,
+    pub min_version: Option<MinVersion>,
+    pub uuid: bool,
+}
+
+impl Layout {
+    /// Read the layout of the thin file `ofile`, `payload` being the bytes of that slice.
+    pub fn new(arch: &str, ofile: &OFile, payload: &[u8]) -> Result<Layout, Error> {
+        let (header, commands) = match *ofile {
+            OFile::MachFile { ref header, ref commands } => (header, commands),
+            _ => bail!(RecompileError::UnsupportedFile(format!("{} is not a thin Mach-O file", arch))),
+        };
+
+        let mut layout = Layout {
+            arch: arch.to_string(),
+            filetype: header.filetype,
+            load_commands: BTreeMap::new(),
+            segments: BTreeMap::new(),
+            sections: BTreeMap::new(),
+            exports: BTreeSet::new(),
+            undefined: BTreeSet::new(),
+            dylibs: Vec::new(),
+            min_version: None,
+            uuid: false,
+        };
+
+        for MachCommand(command, _) in commands {
+            *layout.load_commands.entry(command_name(command)).or_insert(0) += 1;
+            match *command {
+                LoadCommand::Segment { ref segname, vmsize, ref sections, .. } |
+                LoadCommand::Segment64 { ref segname, vmsize, ref sections, .. } => {
+                    layout.segments.insert(segname.clone(), vmsize as u64);
+                    for section in sections {
+                        layout.sections.insert(format!("{},{}", section.segname, section.sectname), section.size as u64);
+                    }
+                },
+                LoadCommand::LoadDyLib(ref dylib) |
+                LoadCommand::ReexportDyLib(ref dylib) |
+                LoadCommand::LoadUpwardDylib(ref dylib) |
+                LoadCommand::LazyLoadDylib(ref dylib) => {
+                    layout.dylibs.push(Dylib { path: dylib.name.1.clone(), weak: false });
+                },
+                LoadCommand::LoadWeakDyLib(ref dylib) => {
+                    layout.dylibs.push(Dylib { path: dylib.name.1.clone(), weak: true });
+                },
+                LoadCommand::VersionMin { target, version, .. } => {
+                    // named as LC_BUILD_VERSION names them, the two compare equal
+                    let platform = build_platform(match target {
+                        BuildTarget::MacOsX => 1,
+                        BuildTarget::IPhoneOs => 2,
+                        BuildTarget::TvOs => 3,
+                        BuildTarget::WatchOs => 4,
+                    });
+                    layout.min_version = Some(MinVersion::new(&platform, version.into()));
+                },
+                LoadCommand::Command { cmd: LC_BUILD_VERSION, ref payload } if payload.len() >= 8 => {
+                    let platform = build_platform(LittleEndian::read_u32(&payload[0..4]));
+                    layout.min_version = Some(MinVersion::new(&platform, LittleEndian::read_u32(&payload[4..8])));
+                },
+                LoadCommand::Uuid(_) => layout.uuid = true,
+                _ => {},
+            }
+        }
+
+        let mut cur = Cursor::new(payload);
+        if let Some(symbols) = ofile.symbols(&mut cur) {
+            for symbol in symbols {
+                match symbol {
+                    Symbol::Defined { name: Some(name), external: true, .. } |
+                    Symbol::Absolute { name: Some(name), external: true, .. } |
+                    Symbol::Indirect { name: Some(name), external: true, .. } => {
+                        layout.exports.insert(name.to_string());
+                    },
+                    Symbol::Undefined { name: Some(name), .. } => {
+                        layout.undefined.insert(name.to_string());
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        Ok(layout)
+    }
+}
+
+/// How much a difference matters for running the recompiled binary in place of the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// expected from a recompilation
+    Info,
+    /// may change the behaviour, worth a look
+    Warning,
+    /// the recompiled binary cannot replace the original
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // padded, the report lines them up in a column
+        f.pad(match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Difference {
+    pub severity: Severity,
+    /// what was compared: `filetype`, `load-commands`, `segments`, `sections`,
+    /// `exports`, `undefined`, `dylibs`, `min-version`, `uuid` or `slices`
+    pub subject: &'static str,
+    pub message: String,
+    /// the symbols, commands or dylibs concerned
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<String>,
+}
+
+impl Difference {
+    fn new<S: Into<String>>(severity: Severity, subject: &'static str, message: S) -> Difference {
+        Difference {
+            severity,
+            subject,
+            message: message.into(),
+            items: Vec::new(),
+        }
+    }
+
+    fn with_items<'a, I: IntoIterator<Item = &'a String>>(mut self, items: I) -> Difference {
+        self.items = items.into_iter().cloned().collect();
+        self
+    }
+}
+
+/// Overall outcome of a comparison, the worst severity found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Compatible,
+    CompatibleWithWarnings,
+    Incompatible,
+}
+
+impl Verdict {
+    fn of(differences: &[Difference]) -> Verdict {
+        match differences.iter().map(|difference| difference.severity).max() {
+            Some(Severity::Error) => Verdict::Incompatible,
+            Some(Severity::Warning) => Verdict::CompatibleWithWarnings,
+            _ => Verdict::Compatible,
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Verdict::Compatible => write!(f, "compatible"),
+            Verdict::CompatibleWithWarnings => write!(f, "compatible with warnings"),
+            Verdict::Incompatible => write!(f, "incompatible"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SliceComparison {
+    pub arch: String,
+    pub verdict: Verdict,
+    pub differences: Vec<Difference>,
+}
+
+/// Items of `original` missing from `recompiled` and the other way round.
+fn added_removed<'a, T: Ord>(original: &'a BTreeSet<T>, recompiled: &'a BTreeSet<T>) -> (Vec<&'a T>, Vec<&'a T>) {
+    (recompiled.difference(original).collect(), original.difference(recompiled).collect())
+}
+
+/// The `__LLVM` segment and its sections, which hold the bundle of the original and
+/// are never in a relinked binary.
+fn is_bundle(name: &str) -> bool {
+    name == SEGMENT_NAME || name.split(',').next() == Some(SEGMENT_NAME)
+}
+
+fn compare_sizes(subject: &'static str, original: &BTreeMap<String, u64>, recompiled: &BTreeMap<String, u64>,
+                 missing: Severity, added: Severity, differences: &mut Vec<Difference>) {
+    for (name, &size) in original {
+        match recompiled.get(name) {
+            None if is_bundle(name) => differences.push(Difference::new(Severity::Info, subject, format!("{} held the bitcode and is dropped", name))),
+            None => differences.push(Difference::new(missing, subject, format!("{} is missing", name))),
+            Some(&new_size) if new_size != size => {
+                let delta = new_size as i64 - size as i64;
+                differences.push(Difference::new(Severity::Info, subject, format!("{} is {} bytes, was {} ({:+})", name, new_size, size, delta)));
+            },
+            _ => {},
+        }
+    }
+    for name in recompiled.keys().filter(|name| !original.contains_key(*name)) {
+        differences.push(Difference::new(added, subject, format!("{} is new", name)));
+    }
+}
+
+fn compare_symbols(subject: &'static str, what: &str, original: &BTreeSet<String>, recompiled: &BTreeSet<String>,
+                   missing: Severity, added: Severity, differences: &mut Vec<Difference>) {
+    let (new, gone) = added_removed(original, recompiled);
+    if !gone.is_empty() {
+        differences.push(Difference::new(missing, subject, format!("{} {} symbols missing", gone.len(), what)).with_items(gone));
+    }
+    if !new.is_empty() {
+        differences.push(Difference::new(added, subject, format!("{} {} symbols added", new.len(), what)).with_items(new));
+    }
+}
+
+fn compare_dylibs(original: &[Dylib], recompiled: &[Dylib], differences: &mut Vec<Difference>) {
+    for dylib in original {
+        match recompiled.iter().find(|other| other.path == dylib.path) {
+            None => differences.push(Difference::new(Severity::Warning, "dylibs", format!("{} is no longer linked", dylib.path))),
+            Some(other) if dylib.weak && !other.weak => {
+                // a strong link fails to load where the original ran without the library
+                differences.push(Difference::new(Severity::Warning, "dylibs", format!("{} is no longer weak", dylib.path)));
+            },
+            Some(other) if !dylib.weak && other.weak => {
+                differences.push(Difference::new(Severity::Info, "dylibs", format!("{} is now weak", dylib.path)));
+            },
+            _ => {},
+        }
+    }
+    for dylib in recompiled.iter().filter(|dylib| !original.iter().any(|other| other.path == dylib.path)) {
+        differences.push(Difference::new(Severity::Warning, "dylibs", format!("{} is newly linked", dylib.path)));
+    }
+}
+
+fn compare_min_version(original: &Option<MinVersion>, recompiled: &Option<MinVersion>, differences: &mut Vec<Difference>) {
+    match (original, recompiled) {
+        (Some(original), Some(recompiled)) if original.platform != recompiled.platform => {
+            differences.push(Difference::new(Severity::Error, "min-version", format!("built for {}, was {}", recompiled, original)));
+        },
+        (Some(original), Some(recompiled)) if recompiled.packed > original.packed => {
+            // devices the original supports would refuse to load it
+            differences.push(Difference::new(Severity::Error, "min-version", format!("requires {}, was {}", recompiled, original)));
+        },
+        (Some(original), Some(recompiled)) if recompiled.packed < original.packed => {
+            differences.push(Difference::new(Severity::Info, "min-version", format!("requires {}, was {}", recompiled, original)));
+        },
+        (Some(original), None) => {
+            differences.push(Difference::new(Severity::Warning, "min-version", format!("no minimum version, was {}", original)));
+        },
+        _ => {},
+    }
+}
+
+/// Compare two slices of the same architecture.
+pub fn compare_layouts(original: &Layout, recompiled: &Layout) -> SliceComparison {
+    let mut differences = Vec::new();
+
+    if original.filetype != recompiled.filetype {
+        differences.push(Difference::new(Severity::Error, "filetype", format!("file type {} is not the original {}", recompiled.filetype, original.filetype)));
+    }
+
+    let original_commands = original.load_commands.keys().cloned().collect::<BTreeSet<_>>();
+    let recompiled_commands = recompiled.load_commands.keys().cloned().collect::<BTreeSet<_>>();
+    let (new, gone) = added_removed(&original_commands, &recompiled_commands);
+    if !gone.is_empty() {
+        differences.push(Difference::new(Severity::Warning, "load-commands", format!("{} load commands dropped", gone.len())).with_items(gone));
+    }
+    if !new.is_empty() {
+        differences.push(Difference::new(Severity::Info, "load-commands", format!("{} load commands added", new.len())).with_items(new));
+    }
+
+    compare_sizes("segments", &original.segments, &recompiled.segments, Severity::Error, Severity::Warning, &mut differences);
+    compare_sizes("sections", &original.sections, &recompiled.sections, Severity::Warning, Severity::Info, &mut differences);
+    compare_symbols("exports", "exported", &original.exports, &recompiled.exports, Severity::Error, Severity::Info, &mut differences);
+    compare_symbols("undefined", "undefined", &original.undefined, &recompiled.undefined, Severity::Info, Severity::Warning, &mut differences);
+    compare_dylibs(&original.dylibs, &recompiled.dylibs, &mut differences);
+    compare_min_version(&original.min_version, &recompiled.min_version, &mut differences);
+
+    match (original.uuid, recompiled.uuid) {
+        (true, false) => differences.push(Difference::new(Severity::Warning, "uuid", "no LC_UUID, crash reports cannot be symbolicated")),
+        (false, true) => differences.push(Difference::new(Severity::Info, "uuid", "LC_UUID added")),
+        _ => {},
+    }
+
+    SliceComparison {
+        arch: original.arch.clone(),
+        verdict: Verdict::of(&differences),
+        differences,
+    }
+}
+
+/// The comparison of every slice of a recompiled binary with the original.
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub original: String,
+    pub recompiled: String,
+    pub verdict: Verdict,
+    /// differences that concern the files rather than one slice
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub differences: Vec<Difference>,
+    pub slices: Vec<SliceComparison>,
+}
+
+fn layouts(file_name: &str, payload: &[u8]) -> Result<Vec<Layout>, Error> {
+    if elf::is_elf(payload) {
+        bail!(RecompileError::UnsupportedFile(file_name.to_string()));
+    }
+    let ofile = OFile::parse(&mut Cursor::new(payload))?;
+    let layouts = thin_files(&ofile, payload)?
+        .into_iter()
+        .map(|file| Layout::new(&file.arch, file.ofile, file.payload))
+        .collect::<Result<Vec<_>, _>>()?;
+    if layouts.is_empty() {
+        bail!(RecompileError::UnsupportedFile(file_name.to_string()));
+    }
+    Ok(layouts)
+}
+
+/// Compare the slices of `recompiled` with the slices of the same architecture in `original`.
+///
+/// A thin recompiled file is only compared with its own architecture, every slice of
+/// the original is expected otherwise.
+pub fn compare(original_name: &str, original: &[u8], recompiled_name: &str, recompiled: &[u8]) -> Result<Comparison, Error> {
+    let original_layouts = layouts(original_name, original)?;
+    let recompiled_layouts = layouts(recompiled_name, recompiled)?;
+
+    let mut differences = Vec::new();
+    let mut slices = Vec::new();
+    for layout in &original_layouts {
+        match recompiled_layouts.iter().find(|other| other.arch == layout.arch) {
+            Some(other) => slices.push(compare_layouts(layout, other)),
+            None if recompiled_layouts.len() == 1 => {
+                differences.push(Difference::new(Severity::Info, "slices", format!("slice {} is not compared", layout.arch)));
+            },
+            None => differences.push(Difference::new(Severity::Error, "slices", format!("slice {} is missing", layout.arch))),
+        }
+    }
+    for layout in recompiled_layouts.iter().filter(|layout| !original_layouts.iter().any(|other| other.arch == layout.arch)) {
+        differences.push(Difference::new(Severity::Error, "slices", format!("slice {} is not in the original", layout.arch)));
+    }
+
+    let verdict = slices.iter()
+        .map(|slice| slice.verdict)
+        .fold(Verdict::of(&differences), Verdict::max);
+    Ok(Comparison {
+        original: original_name.to_string(),
+        recompiled: recompiled_name.to_string(),
+        verdict,
+        differences,
+        slices,
+    })
+}
+
+fn write_difference(f: &mut fmt::Formatter, indent: &str, difference: &Difference) -> fmt::Result {
+    writeln!(f, "{}{:<8} {:<14} {}", indent, difference.severity, difference.subject, difference.message)?;
+    for item in difference.items.iter().take(LISTED_ITEMS) {
+        writeln!(f, "{}{:24}{}", indent, "", item)?;
+    }
+    if difference.items.len() > LISTED_ITEMS {
+        writeln!(f, "{}{:24}... {} more", indent, "", difference.items.len() - LISTED_ITEMS)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} against {}: {}", self.recompiled, self.original, self.verdict)?;
+        for difference in &self.differences {
+            write_difference(f, "  ", difference)?;
+        }
+        for slice in &self.slices {
+            writeln!(f, "  {}: {}", slice.arch, slice.verdict)?;
+            for difference in &slice.differences {
+                write_difference(f, "    ", difference)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(segments: &[(&str, u64)], sections: &[(&str, u64)]) -> Layout {
+        Layout {
+            arch: "arm64".to_string(),
+            filetype: 2,
+            load_commands: vec![("LC_SEGMENT_64".to_string(), segments.len())].into_iter().collect(),
+            segments: segments.iter().map(|&(name, size)| (name.to_string(), size)).collect(),
+            sections: sections.iter().map(|&(name, size)| (name.to_string(), size)).collect(),
+            exports: vec!["_main".to_string()].into_iter().collect(),
+            undefined: vec!["_printf".to_string()].into_iter().collect(),
+            dylibs: vec![Dylib { path: "/usr/lib/libSystem.B.dylib".to_string(), weak: false }],
+            min_version: None,
+            uuid: true,
+        }
+    }
+
+    #[test]
+    fn bundle_segment_is_not_compared() {
+        let original = layout(&[("__TEXT", 0x4000), ("__LINKEDIT", 0x4000), ("__LLVM", 0x8000)],
+                              &[("__TEXT,__text", 0x100), ("__LLVM,__bundle", 0x7f00)]);
+        let recompiled = layout(&[("__TEXT", 0x4000), ("__LINKEDIT", 0x4000)], &[("__TEXT,__text", 0x100)]);
+
+        let comparison = compare_layouts(&original, &recompiled);
+        assert_eq!(comparison.verdict, Verdict::Compatible);
+        assert!(comparison.differences.iter().all(|difference| difference.severity == Severity::Info));
+        assert_eq!(comparison.differences.len(), 2);
+    }
+
+    #[test]
+    fn missing_segment_is_incompatible() {
+        let original = layout(&[("__TEXT", 0x4000), ("__DATA", 0x4000)], &[]);
+        let recompiled = layout(&[("__TEXT", 0x4000)], &[]);
+        assert_eq!(compare_layouts(&original, &recompiled).verdict, Verdict::Incompatible);
+    }
+}
@@ -2,15 +2,13 @@ extern crate failure;
 
 use self::failure::Error;
 
-use std::path::Path;
-use std::io::{Cursor, Read, Write, stderr};
+use std::io::{Cursor, Read, Write};
+use std::path::PathBuf;
 
-const DEFAULT_IPHONE_SDK: &'static str = "/Applications/Xcode.app/Contents/Developer/Platforms/iPhoneOS.platform/Developer/SDKs/iPhoneOS.sdk";
-const DEFAULT_MACOSX_SDK: &'static str = "/Applications/Xcode.app/Contents/Developer/Platforms/MacOSX.platform/Developer/SDKs/MacOSX.sdk";
-const DEFAULT_TOOLCHAIN: &'static str = "/Applications/Xcode.app/Contents/Developer/Toolchains/XcodeDefault.xctoolchain/";
-
-const LIB_CLANG_OSX: &'static str = "libclang_rt.osx.a";
-const LIB_CLANG_IOS: &'static str = "libclang_rt.ios.a";
+use error::RecompileError;
+use platform;
+use runtime;
+use toolchain::{self, Profile, Source, ToolchainOptions, Tools};
 
 pub struct FileContext<'a> {
     pub cur: Cursor<&'a [u8]>,
@@ -29,7 +27,7 @@ impl<'a> FileContext<'a> {
         for off in 0..size {
             if (off % 16) == 0 {
                 if off > 0 {
-                    writeln!(&mut w, "")?;
+                    writeln!(&mut w)?;
                 }
 
                 write!(&mut w, "{:016x}\t", addr + off)?;
@@ -38,7 +36,7 @@ impl<'a> FileContext<'a> {
             write!(&mut w, "{:02x} ", self.read_u8()?)?;
         }
 
-        writeln!(&mut w, "")?;
+        writeln!(&mut w)?;
 
         Ok(w)
     }
@@ -58,7 +56,7 @@ impl<'a> FileContext<'a> {
     fn read_u8(&mut self) -> Result<u8, Error> {
 
         let mut buf = [0; 1];
-        try!(self.cur.read_exact(&mut buf));
+        self.cur.read_exact(&mut buf)?;
         Ok(buf[0])
     }
 }
@@ -68,10 +66,19 @@ pub struct ReCompilerInfo {
     pub lib_clang: String,
     pub tool_chain: String,
     pub sdk_path: String,
-    pub obj_file: Vec<String>,
-    pub link_framework: Vec<String>,
-    pub link_options: Vec<String>,
-    pub file_compile: Vec<Vec<String>>,
+    /// which step of the search order of `toolchain` found them
+    pub tool_chain_source: Source,
+    pub sdk_source: Source,
+    pub profile: Profile,
+    pub tools: Tools,
+    /// `lib_clang` in the ToolChain, `None` when the cross profile links without it
+    pub runtime: Option<PathBuf>,
+}
+
+impl Default for ReCompilerInfo {
+    fn default() -> ReCompilerInfo {
+        ReCompilerInfo::new()
+    }
 }
 
 impl ReCompilerInfo {
@@ -82,10 +89,11 @@ impl ReCompilerInfo {
             lib_clang: String::new(),
             tool_chain: String::new(),
             sdk_path: String::new(),
-            obj_file: Vec::new(),
-            link_framework: Vec::new(),
-            link_options: Vec::new(),
-            file_compile: Vec::new(),
+            tool_chain_source: Source::Default,
+            sdk_source: Source::Default,
+            profile: Profile::Xcode,
+            tools: Tools::new(&ToolchainOptions::default()),
+            runtime: None,
         }
     }
 
@@ -93,102 +101,35 @@ impl ReCompilerInfo {
         self.platform = data;
     }
 
-    pub fn push_framework(&mut self, framework: &mut String) {
+    pub fn choose_path_from_platform(&mut self, options: &ToolchainOptions) -> Result<(), Error> {
 
-        if let Some(_) = framework.find("/usr/lib") {
-            return;
-        }
-
-        let off = match framework.rfind('/') {
-            Some(index) => {
-                index + 1
-            }
-            None => {
-                0
-            }
+        let platform = match platform::find(&self.platform) {
+            Some(platform) => platform,
+            None => bail!(RecompileError::UnsupportedPlatform(self.platform.clone())),
         };
-        self.link_framework.push(framework.split_off(off));
-    }
-    pub fn push_option(&mut self, option: String) {
-        self.link_options.push(option);
-    }
-    pub fn add_new_file_vec(&mut self, file_name: String) {
-        self.file_compile.push(Vec::new());
-        self.file_compile.last_mut().unwrap().push(file_name);
-    }
-    pub fn add_file_cmd(&mut self, cmd: String) {
-        self.file_compile.last_mut().unwrap().push(cmd);
-    }
+        self.lib_clang = platform.library();
 
-    pub fn choose_path_from_platform(&mut self, sdk_path: Option<String>, tool_chain: Option<String>) -> Result<bool, Error> {
+        self.profile = options.profile;
 
-        match self.platform.as_ref() {
-            "MacOSX" => {
-                self.lib_clang.push_str(LIB_CLANG_OSX);
-            },
-            "iPhoneOS" => {
-                self.lib_clang.push_str(LIB_CLANG_IOS);
-            },
-            _ => {
-                writeln!(stderr(), "no support platform.");
-                return Ok(false);
-            }
-        };
+        let sdk = toolchain::resolve_sdk(platform, options.sdk.as_deref())?;
+        self.sdk_path = sdk.path.to_string_lossy().into_owned();
+        self.sdk_source = sdk.source;
 
-        match sdk_path {
-            Some(s) => {
-                if !Path::new(&s).exists() {
-                    writeln!(stderr(), "sdk path is not exist.");
-                    return Ok(false);
-                }
-                match s.find(self.platform.as_str()) {
-                    Some(_) => {},
-                    None => {
-                        writeln!(stderr(), "the sdk is inconsistent with the platform.");
-                        return Ok(false);
-                    },
-                };
-                self.sdk_path.push_str(s.as_str());
-            },
-            None => {
-                let s = String::from(match self.platform.as_ref() {
-                    "MacOSX" => {
-                        DEFAULT_MACOSX_SDK
-                    },
-                    "iPhoneOS" => {
-                        DEFAULT_IPHONE_SDK
-                    },
-                    _ => {
-                        writeln!(stderr(), "no support platform.");
-                        return Ok(false);
-                    }
-                });
-                if !Path::new(&s).exists() {
-                    writeln!(stderr(), "no sdk path.");
-                    return Ok(false);
-                }
-                self.sdk_path.push_str(s.as_str());
-            },
-        };
+        let (tool_chain, tools) = toolchain::resolve_tools(options)?;
+        self.tools = tools;
+        self.tool_chain = tool_chain.path.to_string_lossy().into_owned();
+        self.tool_chain_source = tool_chain.source;
 
-        match tool_chain {
-            Some(s) => {
-                if !Path::new(&s).exists() {
-                    writeln!(stderr(), "ToolChain path is not exist.");
-                    return Ok(false);
-                }
-                self.tool_chain.push_str(s.as_str());
-            },
-            None => {
-                if Path::new(DEFAULT_TOOLCHAIN).exists() {
-                    self.tool_chain.push_str(DEFAULT_TOOLCHAIN);
-                } else {
-                    writeln!(stderr(), "no ToolChain path.");
-                    return Ok(false);
-                }
+        self.runtime = match runtime::find_runtime(&tool_chain.path, &self.tools.clang, &self.lib_clang) {
+            Ok(runtime) => Some(runtime),
+            // upstream LLVM is rarely built with the Darwin runtimes
+            Err(e) if self.profile == Profile::Cross => {
+                eprintln!("warning: {}, linking without it", e);
+                None
             },
+            Err(e) => return Err(e),
         };
 
-        return Ok(true);
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,360 @@
+extern crate byteorder;
+extern crate failure;
+
+use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
+use self::failure::Error;
+
+use std::str;
+
+use error::RecompileError;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+const DT_FLAGS_1: u64 = 0x6fff_fffb;
+const DF_1_PIE: u64 = 0x0800_0000;
+
+const PT_INTERP: u32 = 3;
+
+pub fn is_elf(payload: &[u8]) -> bool {
+    payload.starts_with(ELF_MAGIC)
+}
+
+#[derive(Debug, Clone)]
+pub struct ElfSection {
+    pub name: String,
+    pub sh_type: u32,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+}
+
+/// Just enough of an ELF file to find the embedded bitcode and the dynamic dependencies.
+pub struct ElfFile<'a> {
+    pub is_64bit: bool,
+    pub is_bigend: bool,
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub sections: Vec<ElfSection>,
+    payload: &'a [u8],
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(payload: &'a [u8]) -> Result<ElfFile<'a>, Error> {
+        if !is_elf(payload) || payload.len() < 0x34 {
+            bail!(RecompileError::malformed("elf file", "not an elf file"));
+        }
+        let is_64bit = payload[4] == ELFCLASS64;
+        let is_bigend = payload[5] == ELFDATA2MSB;
+
+        let mut elf = ElfFile {
+            is_64bit,
+            is_bigend,
+            e_type: 0,
+            e_machine: 0,
+            sections: Vec::new(),
+            payload,
+        };
+
+        elf.e_type = elf.read_u16(0x10)?;
+        elf.e_machine = elf.read_u16(0x12)?;
+        let (shoff, shentsize, shnum, shstrndx) = if is_64bit {
+            (elf.read_u64(0x28)?, elf.read_u16(0x3a)?, elf.read_u16(0x3c)?, elf.read_u16(0x3e)?)
+        } else {
+            (u64::from(elf.read_u32(0x20)?), elf.read_u16(0x2e)?, elf.read_u16(0x30)?, elf.read_u16(0x32)?)
+        };
+
+        let mut sections = Vec::with_capacity(shnum as usize);
+        let mut names = Vec::with_capacity(shnum as usize);
+        for index in 0..u64::from(shnum) {
+            let off = (shoff + index * u64::from(shentsize)) as usize;
+            let name = elf.read_u32(off)?;
+            let sh_type = elf.read_u32(off + 4)?;
+            let (offset, size, link) = if is_64bit {
+                (elf.read_u64(off + 0x18)?, elf.read_u64(off + 0x20)?, elf.read_u32(off + 0x28)?)
+            } else {
+                (u64::from(elf.read_u32(off + 0x10)?), u64::from(elf.read_u32(off + 0x14)?), elf.read_u32(off + 0x18)?)
+            };
+            names.push(name);
+            sections.push(ElfSection {
+                name: String::new(),
+                sh_type,
+                offset,
+                size,
+                link,
+            });
+        }
+
+        if let Some(strtab) = sections.get(shstrndx as usize).cloned() {
+            let strtab = elf.range(strtab.offset, strtab.size)?;
+            for (sect, name) in sections.iter_mut().zip(names) {
+                sect.name = read_cstr(strtab, name as usize)?;
+            }
+        }
+        elf.sections = sections;
+
+        Ok(elf)
+    }
+
+    fn range(&self, offset: u64, size: u64) -> Result<&'a [u8], Error> {
+        let start = offset as usize;
+        start.checked_add(size as usize)
+            .filter(|end| *end <= self.payload.len())
+            .map(|end| &self.payload[start..end])
+            .ok_or_else(|| RecompileError::malformed("elf file", format!("elf range 0x{:x}+0x{:x} exceeds the file", offset, size)).into())
+    }
+
+    fn read_u16(&self, off: usize) -> Result<u16, Error> {
+        let buf = self.range(off as u64, 2)?;
+        Ok(if self.is_bigend { BigEndian::read_u16(buf) } else { LittleEndian::read_u16(buf) })
+    }
+
+    fn read_u32(&self, off: usize) -> Result<u32, Error> {
+        let buf = self.range(off as u64, 4)?;
+        Ok(if self.is_bigend { BigEndian::read_u32(buf) } else { LittleEndian::read_u32(buf) })
+    }
+
+    fn read_u64(&self, off: usize) -> Result<u64, Error> {
+        let buf = self.range(off as u64, 8)?;
+        Ok(if self.is_bigend { BigEndian::read_u64(buf) } else { LittleEndian::read_u64(buf) })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&'a [u8]> {
+        self.sections.iter()
+            .find(|sect| sect.name == name)
+            .and_then(|sect| self.range(sect.offset, sect.size).ok())
+    }
+
+    /// `(tag, value)` pairs of the `.dynamic` section.
+    fn dynamic_entries(&self) -> Result<Vec<(u64, u64)>, Error> {
+        let dynamic = match self.sections.iter().find(|sect| sect.name == ".dynamic") {
+            Some(sect) => sect,
+            None => return Ok(Vec::new()),
+        };
+
+        let entsize = if self.is_64bit { 16 } else { 8 };
+        let mut entries = Vec::new();
+        for index in 0..dynamic.size / entsize {
+            let off = (dynamic.offset + index * entsize) as usize;
+            let (tag, value) = if self.is_64bit {
+                (self.read_u64(off)?, self.read_u64(off + 8)?)
+            } else {
+                (u64::from(self.read_u32(off)?), u64::from(self.read_u32(off + 4)?))
+            };
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, value));
+        }
+
+        Ok(entries)
+    }
+
+    /// `(tag, value)` pairs of the `.dynamic` section with string values resolved.
+    fn dynamic_strings(&self) -> Result<Vec<(u64, String)>, Error> {
+        let strtab = match self.sections.iter().find(|sect| sect.name == ".dynamic").and_then(|sect| self.sections.get(sect.link as usize)) {
+            Some(sect) => self.range(sect.offset, sect.size)?,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut entries = Vec::new();
+        for (tag, value) in self.dynamic_entries()? {
+            if let DT_NEEDED | DT_SONAME | DT_RPATH | DT_RUNPATH = tag {
+                entries.push((tag, read_cstr(strtab, value as usize)?));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// `p_type` of every program header.
+    fn program_types(&self) -> Result<Vec<u32>, Error> {
+        let (phoff, phentsize, phnum) = if self.is_64bit {
+            (self.read_u64(0x20)?, self.read_u16(0x36)?, self.read_u16(0x38)?)
+        } else {
+            (u64::from(self.read_u32(0x1c)?), self.read_u16(0x2a)?, self.read_u16(0x2c)?)
+        };
+        (0..u64::from(phnum))
+            .map(|index| self.read_u32((phoff + index * u64::from(phentsize)) as usize))
+            .collect()
+    }
+
+    /// Whether an `ET_DYN` file is a position independent executable rather than a shared
+    /// object: it is flagged `DF_1_PIE`, or has an interpreter and no soname. `libc.so.6`
+    /// has an interpreter too, to run on its own.
+    pub fn is_pie(&self) -> Result<bool, Error> {
+        if self.e_type != ET_DYN {
+            return Ok(false);
+        }
+        if self.dynamic_entries()?.iter().any(|&(tag, value)| tag == DT_FLAGS_1 && value & DF_1_PIE != 0) {
+            return Ok(true);
+        }
+        Ok(self.program_types()?.contains(&PT_INTERP) && self.soname()?.is_none())
+    }
+
+    pub fn needed(&self) -> Result<Vec<String>, Error> {
+        Ok(self.dynamic_strings()?
+            .into_iter()
+            .filter(|&(tag, _)| tag == DT_NEEDED)
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    pub fn soname(&self) -> Result<Option<String>, Error> {
+        Ok(self.dynamic_strings()?
+            .into_iter()
+            .find(|&(tag, _)| tag == DT_SONAME)
+            .map(|(_, value)| value))
+    }
+
+    pub fn runpath(&self) -> Result<Vec<String>, Error> {
+        Ok(self.dynamic_strings()?
+            .into_iter()
+            .filter(|&(tag, _)| tag == DT_RPATH || tag == DT_RUNPATH)
+            .map(|(_, value)| value)
+            .collect())
+    }
+}
+
+fn read_cstr(strtab: &[u8], off: usize) -> Result<String, Error> {
+    let tail = strtab.get(off..).ok_or_else(|| RecompileError::malformed("elf file", format!("string offset {} out of range", off)))?;
+    let end = tail.iter().position(|c| *c == 0).unwrap_or(tail.len());
+    Ok(str::from_utf8(&tail[..end])?.to_string())
+}
+
+/// Split the `.llvmcmd` section, every cc1 command line of a module begins with `-triple`.
+pub fn split_cmdlines(data: &[u8]) -> Vec<Vec<String>> {
+    let mut cmdlines: Vec<Vec<String>> = Vec::new();
+    for arg in data.split(|c| *c == 0).filter(|arg| !arg.is_empty()) {
+        let arg = String::from_utf8_lossy(arg).into_owned();
+        if arg == "-triple" || cmdlines.is_empty() {
+            cmdlines.push(Vec::new());
+        }
+        if let Some(cmdline) = cmdlines.last_mut() {
+            cmdline.push(arg);
+        }
+    }
+    cmdlines
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate byteorder;
+
+    use self::byteorder::{LittleEndian, WriteBytesExt};
+    use super::*;
+
+    /// A 64 bit little endian ELF file with an optional `PT_INTERP` and the sections
+    /// `.dynstr`, `.dynamic` and `.shstrtab`.
+    fn elf_file(e_type: u16, interp: bool, dynstr: &[u8], dynamic: &[(u64, u64)]) -> Vec<u8> {
+        let shstrtab = b"\0.dynstr\0.dynamic\0.shstrtab\0";
+        let phnum = if interp { 1 } else { 0 };
+        let dynstr_off = 64 + 56 * phnum;
+        let shstrtab_off = dynstr_off + dynstr.len() as u64;
+        let dynamic_off = shstrtab_off + shstrtab.len() as u64;
+        let dynamic_size = (dynamic.len() as u64 + 1) * 16;
+        let shoff = dynamic_off + dynamic_size;
+
+        let mut w = Vec::new();
+        w.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        w.resize(0x10, 0);
+        w.write_u16::<LittleEndian>(e_type).unwrap();
+        w.write_u16::<LittleEndian>(62).unwrap();
+        w.write_u32::<LittleEndian>(1).unwrap();
+        w.write_u64::<LittleEndian>(0).unwrap();
+        w.write_u64::<LittleEndian>(if interp { 64 } else { 0 }).unwrap();
+        w.write_u64::<LittleEndian>(shoff).unwrap();
+        w.write_u32::<LittleEndian>(0).unwrap();
+        w.write_u16::<LittleEndian>(64).unwrap();
+        w.write_u16::<LittleEndian>(56).unwrap();
+        w.write_u16::<LittleEndian>(phnum as u16).unwrap();
+        w.write_u16::<LittleEndian>(64).unwrap();
+        w.write_u16::<LittleEndian>(4).unwrap();
+        w.write_u16::<LittleEndian>(3).unwrap();
+        if interp {
+            w.write_u32::<LittleEndian>(PT_INTERP).unwrap();
+            w.resize(64 + 56, 0);
+        }
+        w.extend_from_slice(dynstr);
+        w.extend_from_slice(shstrtab);
+        for &(tag, value) in dynamic.iter().chain([(DT_NULL, 0)].iter()) {
+            w.write_u64::<LittleEndian>(tag).unwrap();
+            w.write_u64::<LittleEndian>(value).unwrap();
+        }
+
+        // (name, type, offset, size, link)
+        let sections = [
+            (0, 0, 0, 0, 0),
+            (1, 3, dynstr_off, dynstr.len() as u64, 0),
+            (9, 6, dynamic_off, dynamic_size, 1),
+            (18, 3, shstrtab_off, shstrtab.len() as u64, 0),
+        ];
+        for &(name, sh_type, offset, size, link) in &sections {
+            w.write_u32::<LittleEndian>(name).unwrap();
+            w.write_u32::<LittleEndian>(sh_type).unwrap();
+            w.write_u64::<LittleEndian>(0).unwrap();
+            w.write_u64::<LittleEndian>(0).unwrap();
+            w.write_u64::<LittleEndian>(offset).unwrap();
+            w.write_u64::<LittleEndian>(size).unwrap();
+            w.write_u32::<LittleEndian>(link).unwrap();
+            w.write_u32::<LittleEndian>(0).unwrap();
+            w.write_u64::<LittleEndian>(0).unwrap();
+            w.write_u64::<LittleEndian>(0).unwrap();
+        }
+        w
+    }
+
+    #[test]
+    fn split_llvmcmd() {
+        let data = b"-triple\0x86_64-unknown-linux-gnu\0-O2\0\0-triple\0aarch64-linux-android\0-Os\0";
+        assert_eq!(split_cmdlines(data), vec![
+            vec!["-triple", "x86_64-unknown-linux-gnu", "-O2"],
+            vec!["-triple", "aarch64-linux-android", "-Os"],
+        ]);
+        // a command line recorded without -triple still counts
+        assert_eq!(split_cmdlines(b"-O2\0-g\0"), vec![vec!["-O2", "-g"]]);
+        assert!(split_cmdlines(b"\0\0").is_empty());
+    }
+
+    #[test]
+    fn shared_object_is_not_pie() {
+        let data = elf_file(ET_DYN, false, b"\0libfoo.so\0", &[(DT_SONAME, 1)]);
+        let elf = ElfFile::parse(&data).unwrap();
+        assert_eq!(elf.soname().unwrap(), Some("libfoo.so".to_string()));
+        assert!(!elf.is_pie().unwrap());
+    }
+
+    #[test]
+    fn flagged_pie() {
+        let data = elf_file(ET_DYN, false, b"\0", &[(DT_FLAGS_1, DF_1_PIE)]);
+        assert!(ElfFile::parse(&data).unwrap().is_pie().unwrap());
+    }
+
+    #[test]
+    fn interpreter_without_soname_is_pie() {
+        let data = elf_file(ET_DYN, true, b"\0", &[]);
+        assert!(ElfFile::parse(&data).unwrap().is_pie().unwrap());
+    }
+
+    #[test]
+    fn libc_with_interpreter_is_not_pie() {
+        let data = elf_file(ET_DYN, true, b"\0libc.so.6\0", &[(DT_SONAME, 1)]);
+        assert!(!ElfFile::parse(&data).unwrap().is_pie().unwrap());
+    }
+
+    #[test]
+    fn executable_is_not_pie() {
+        let data = elf_file(ET_EXEC, true, b"\0", &[]);
+        assert!(!ElfFile::parse(&data).unwrap().is_pie().unwrap());
+    }
+}
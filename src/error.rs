@@ -0,0 +1,115 @@
+/// Why a recompilation stage failed.
+///
+/// Stages return `failure::Error`, callers get the reason back with
+/// `err.downcast_ref::<RecompileError>()`.
+#[derive(Debug, Fail)]
+pub enum RecompileError {
+    #[fail(display = "no __LLVM,__bundle section in {}", _0)]
+    MissingBundle(String),
+
+    #[fail(display = "no embedded bitcode in {}", _0)]
+    MissingBitcode(String),
+
+    #[fail(display = "malformed xar archive: {}", _0)]
+    MalformedXar(String),
+
+    #[fail(display = "malformed {}: {}", _0, _1)]
+    MalformedFile(String, String),
+
+    #[fail(display = "unsupported file: {}", _0)]
+    UnsupportedFile(String),
+
+    #[fail(display = "unsupported platform \"{}\"", _0)]
+    UnsupportedPlatform(String),
+
+    #[fail(display = "sdk not found: {}", _0)]
+    SdkNotFound(String),
+
+    #[fail(display = "the sdk {} is inconsistent with the platform {}", sdk, platform)]
+    SdkMismatch { sdk: String, platform: String },
+
+    #[fail(display = "ToolChain not found: {}", _0)]
+    ToolChainNotFound(String),
+
+    #[fail(display = "clang runtime not found: {}", _0)]
+    RuntimeNotFound(String),
+
+    #[fail(display = "{}\nrerun with --force to compile anyway", _0)]
+    ToolChainMismatch(String),
+
+    #[fail(display = "file {} compiled failed ({})\n{}", file, status, log)]
+    CompileFailed { file: String, status: ExitStatus, log: String },
+
+    #[fail(display = "{} of {} modules failed to compile\n{}", count, total, failures)]
+    ModulesFailed { count: usize, total: usize, failures: ModuleFailures },
+
+    #[fail(display = "{} timed out after {}s", program, seconds)]
+    Timeout { program: String, seconds: u64 },
+
+    #[fail(display = "link {} failed ({})\n{}", output, status, log)]
+    LinkFailed { output: String, status: ExitStatus, log: String },
+}
+
+impl RecompileError {
+    pub fn malformed<S: Into<String>>(kind: &str, reason: S) -> RecompileError {
+        RecompileError::MalformedFile(kind.to_string(), reason.into())
+    }
+}
+
+/// One module that did not compile and why.
+#[derive(Debug, Clone)]
+pub struct ModuleFailure {
+    pub file: String,
+    pub reason: String,
+}
+
+impl ModuleFailure {
+    /// Summarize `err` on one line, a failed compile is reduced to its first diagnostic.
+    pub fn new(file: &str, err: &::failure::Error) -> ModuleFailure {
+        let reason = match err.downcast_ref::<RecompileError>() {
+            Some(RecompileError::CompileFailed { status, log, .. }) => {
+                match log.lines().find(|line| line.contains("error")) {
+                    Some(line) => format!("{}: {}", status, line.trim()),
+                    None => status.to_string(),
+                }
+            },
+            _ => err.to_string(),
+        };
+
+        ModuleFailure {
+            file: file.to_string(),
+            reason,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleFailures(pub Vec<ModuleFailure>);
+
+impl ::std::fmt::Display for ModuleFailures {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        for failure in &self.0 {
+            writeln!(f, "  {}: {}", failure.file, failure.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a tool exited, `None` when it was killed by a signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExitStatus(pub Option<i32>);
+
+impl ::std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.0 {
+            Some(code) => write!(f, "exit status {}", code),
+            None => write!(f, "terminated by signal"),
+        }
+    }
+}
+
+impl From<::std::process::ExitStatus> for ExitStatus {
+    fn from(status: ::std::process::ExitStatus) -> ExitStatus {
+        ExitStatus(status.code())
+    }
+}
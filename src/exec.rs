@@ -0,0 +1,82 @@
+extern crate failure;
+
+use self::failure::Error;
+
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use error::{ExitStatus, RecompileError};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What a tool run by `run` left behind.
+#[derive(Debug, Clone)]
+pub struct ToolOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    pub elapsed: Duration,
+}
+
+impl ToolOutput {
+    pub fn success(&self) -> bool {
+        self.status.0 == Some(0)
+    }
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut data);
+        }
+        String::from_utf8_lossy(&data).into_owned()
+    })
+}
+
+fn program_name(command: &Command) -> String {
+    command.get_program().to_string_lossy().into_owned()
+}
+
+/// Run `command` to completion with stdout and stderr captured.
+///
+/// The child is killed once it runs longer than `timeout`. A non-zero exit is not
+/// an error here, callers look at `ToolOutput::status`.
+pub fn run(command: &mut Command, timeout: Option<Duration>) -> Result<ToolOutput, Error> {
+    let program = program_name(command);
+    let start = Instant::now();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format_err!("cannot run {}: {}", program, e))?;
+    // drain both pipes while waiting, a chatty child would block on a full pipe otherwise
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let status = match timeout {
+        None => child.wait()?,
+        Some(timeout) => loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if start.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                // the readers are left behind, a grandchild may still hold the pipes
+                bail!(RecompileError::Timeout { program, seconds: timeout.as_secs() });
+            }
+            thread::sleep(POLL_INTERVAL);
+        },
+    };
+
+    Ok(ToolOutput {
+        status: status.into(),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+        elapsed: start.elapsed(),
+    })
+}
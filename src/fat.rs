@@ -0,0 +1,81 @@
+extern crate byteorder;
+extern crate failure;
+extern crate mach_object;
+
+use self::byteorder::{BigEndian, WriteBytesExt};
+use self::failure::Error;
+use mach_object::{cpu_subtype_t, cpu_type_t, get_arch_name_from_types, FatArch, FAT_MAGIC};
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use error::RecompileError;
+
+const FAT_HEADER_SIZE: u32 = 8;
+const FAT_ARCH_SIZE: u32 = 20;
+
+/// The `-arch` style name of a cpu type, e.g. `arm64` or `x86_64`.
+pub fn cpu_name(cputype: cpu_type_t, cpusubtype: cpu_subtype_t) -> String {
+    match get_arch_name_from_types(cputype, cpusubtype) {
+        Some(name) => name.to_string(),
+        None => format!("cpu{}", cputype),
+    }
+}
+
+/// The `-arch` style name of a slice.
+pub fn arch_name(arch: &FatArch) -> String {
+    cpu_name(arch.cputype, arch.cpusubtype)
+}
+
+/// Where a single slice is linked before being merged into `output`.
+pub fn slice_output(output: &str, arch_name: &str) -> String {
+    format!("{}.{}", output, arch_name)
+}
+
+/// Rebuild a universal binary from recompiled slices.
+///
+/// The slices keep the order and alignment of the original `fat_arch` table,
+/// only offsets and sizes are recomputed from the new slice files.
+pub fn write_universal(slices: &[(FatArch, String)], output: &Path) -> Result<(), Error> {
+    let mut payloads = Vec::with_capacity(slices.len());
+    for (_, path) in slices {
+        payloads.push(fs::read(path)?);
+    }
+
+    let mut archs = Vec::with_capacity(slices.len());
+    let mut offset = FAT_HEADER_SIZE + FAT_ARCH_SIZE * slices.len() as u32;
+    for ((arch, _), payload) in slices.iter().zip(payloads.iter()) {
+        let align = 1u32.checked_shl(arch.align)
+            .ok_or_else(|| RecompileError::malformed("fat file", format!("bad fat alignment 2^{}", arch.align)))?;
+        offset = offset.div_ceil(align) * align;
+
+        archs.push(FatArch {
+            cputype: arch.cputype,
+            cpusubtype: arch.cpusubtype,
+            offset,
+            size: payload.len() as u32,
+            align: arch.align,
+        });
+        offset += payload.len() as u32;
+    }
+
+    let mut w = Vec::with_capacity(offset as usize);
+    w.write_u32::<BigEndian>(FAT_MAGIC)?;
+    w.write_u32::<BigEndian>(archs.len() as u32)?;
+    for arch in &archs {
+        w.write_u32::<BigEndian>(arch.cputype as u32)?;
+        w.write_u32::<BigEndian>(arch.cpusubtype as u32)?;
+        w.write_u32::<BigEndian>(arch.offset)?;
+        w.write_u32::<BigEndian>(arch.size)?;
+        w.write_u32::<BigEndian>(arch.align)?;
+    }
+    for (arch, payload) in archs.iter().zip(payloads.iter()) {
+        w.resize(arch.offset as usize, 0);
+        w.write_all(payload)?;
+    }
+
+    fs::write(output, w)?;
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+extern crate failure;
+extern crate mach_object;
+
+use self::failure::Error;
+use mach_object::OFile;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Cursor;
+
+use elf;
+use error::RecompileError;
+use manifest::{BundleManifest, Dylib, FileType};
+use pipeline::{self, Bundle, ModuleHeaders};
+
+/// What the `__LLVM,__bundle` section of one slice holds.
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleInfo {
+    /// file offset of the section within the slice
+    pub offset: u64,
+    pub size: u64,
+    pub version: String,
+    pub platform: String,
+    pub sdkversion: String,
+    pub architecture: String,
+    /// number of modules by kind, bitcode compiled by swift is counted as `Swift`
+    pub modules: BTreeMap<String, usize>,
+    /// number of bitcode modules by the compiler that produced them, `unknown` when
+    /// the module has no `IDENTIFICATION` block
+    pub producers: BTreeMap<String, usize>,
+    /// why the header of a bitcode module could not be read, those count as `unknown`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unreadable: Vec<String>,
+    pub dylibs: Vec<Dylib>,
+    pub link_options: Vec<String>,
+}
+
+impl BundleInfo {
+    pub fn new(bundle: &Bundle, manifest: &BundleManifest, headers: &ModuleHeaders) -> BundleInfo {
+        let mut modules = BTreeMap::new();
+        for file in &manifest.files {
+            let kind = if file.file_type == FileType::Bitcode && file.is_swift() {
+                "Swift"
+            } else {
+                file.file_type.as_str()
+            };
+            *modules.entry(kind.to_string()).or_insert(0) += 1;
+        }
+        let mut producers = BTreeMap::new();
+        for (_, header) in &headers.headers {
+            let producer = header.producer.clone().unwrap_or_else(|| "unknown".to_string());
+            *producers.entry(producer).or_insert(0) += 1;
+        }
+        if !headers.unreadable.is_empty() {
+            *producers.entry("unknown".to_string()).or_insert(0) += headers.unreadable.len();
+        }
+
+        BundleInfo {
+            offset: bundle.offset,
+            size: bundle.data.len() as u64,
+            version: manifest.version.clone(),
+            platform: manifest.platform.clone(),
+            sdkversion: manifest.sdkversion.clone(),
+            architecture: manifest.architecture.clone(),
+            modules,
+            producers,
+            unreadable: headers.unreadable.clone(),
+            dylibs: manifest.dylibs.clone(),
+            link_options: manifest.link_options.clone(),
+        }
+    }
+}
+
+/// One thin Mach-O file or one slice of a fat file.
+#[derive(Debug, Clone, Serialize)]
+pub struct SliceInfo {
+    pub arch: String,
+    /// `None` when the slice has no `__LLVM,__bundle` section
+    pub bundle: Option<BundleInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub file: String,
+    pub slices: Vec<SliceInfo>,
+}
+
+/// Describe the bitcode bundles of `payload` without extracting or compiling anything.
+pub fn inspect(file_name: &str, payload: &[u8]) -> Result<FileInfo, Error> {
+    if elf::is_elf(payload) {
+        bail!(RecompileError::MissingBundle(file_name.to_string()));
+    }
+
+    let ofile = OFile::parse(&mut Cursor::new(payload))?;
+    let mut slices = Vec::new();
+    for (arch, bundle) in pipeline::find_bundles(&ofile, payload)? {
+        let bundle = match bundle {
+            Some(bundle) => {
+                let manifest = pipeline::parse_manifest(&bundle)?;
+                let headers = pipeline::module_headers(&bundle, &manifest)?;
+                Some(BundleInfo::new(&bundle, &manifest, &headers))
+            },
+            None => None,
+        };
+        slices.push(SliceInfo { arch, bundle });
+    }
+    if slices.is_empty() {
+        bail!(RecompileError::UnsupportedFile(file_name.to_string()));
+    }
+
+    Ok(FileInfo {
+        file: file_name.to_string(),
+        slices,
+    })
+}
+
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}:", self.file)?;
+        for slice in &self.slices {
+            let bundle = match slice.bundle {
+                Some(ref bundle) => bundle,
+                None => {
+                    writeln!(f, "  {}: no __LLVM,__bundle section", slice.arch)?;
+                    continue;
+                },
+            };
+            writeln!(f, "  {}: __LLVM,__bundle at offset 0x{:x}, {} bytes", slice.arch, bundle.offset, bundle.size)?;
+            writeln!(f, "    version:      {}", bundle.version)?;
+            writeln!(f, "    platform:     {}", bundle.platform)?;
+            writeln!(f, "    sdk version:  {}", bundle.sdkversion)?;
+            writeln!(f, "    architecture: {}", bundle.architecture)?;
+            let modules = bundle.modules.iter()
+                .map(|(kind, count)| format!("{} {}", count, kind))
+                .collect::<Vec<_>>();
+            writeln!(f, "    modules:      {}", modules.join(", "))?;
+            let producers = bundle.producers.iter()
+                .map(|(producer, count)| format!("{} ({})", producer, count))
+                .collect::<Vec<_>>();
+            writeln!(f, "    producers:    {}", producers.join(", "))?;
+            for unreadable in &bundle.unreadable {
+                writeln!(f, "      unreadable {}", unreadable)?;
+            }
+            writeln!(f, "    dylibs:")?;
+            for dylib in &bundle.dylibs {
+                writeln!(f, "      {}{}", dylib.path, if dylib.weak { " (weak)" } else { "" })?;
+            }
+            writeln!(f, "    link options: {}", bundle.link_options.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcode::ModuleHeader;
+
+    #[test]
+    fn unreadable_modules_are_unknown() {
+        let header = |producer: Option<&str>| ModuleHeader {
+            wrapper: None,
+            producer: producer.map(str::to_string),
+            epoch: Some(0),
+        };
+        let headers = ModuleHeaders {
+            headers: vec![("1".to_string(), header(Some("LLVM14.0.6"))), ("2".to_string(), header(None))],
+            unreadable: vec!["module 3: malformed bitcode: no bitcode magic".to_string()],
+        };
+        let bundle = Bundle { offset: 0x4000, data: Vec::new() };
+        let info = BundleInfo::new(&bundle, &BundleManifest::default(), &headers);
+
+        let producers = info.producers.iter().map(|(producer, count)| (producer.as_str(), *count)).collect::<Vec<_>>();
+        assert_eq!(producers, vec![("LLVM14.0.6", 1), ("unknown", 2)]);
+        let file = FileInfo {
+            file: "App".to_string(),
+            slices: vec![SliceInfo { arch: "arm64".to_string(), bundle: Some(info) }],
+        };
+        assert!(file.to_string().contains("unreadable module 3: malformed bitcode"));
+    }
+}
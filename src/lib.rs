@@ -0,0 +1,40 @@
+//! Extract the bitcode embedded in Mach-O and ELF files and recompile it.
+//!
+//! The bundle flow of a linked Mach-O file is exposed stage by stage in
+//! [`pipeline`](pipeline/index.html), `MachOProcess` and `ElfProcess` drive
+//! every supported input kind end to end.
+
+#[macro_use]
+extern crate failure;
+extern crate mach_object;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod app;
+pub mod archive;
+pub mod batch;
+pub mod bitcode;
+pub mod compare;
+pub mod context;
+pub mod elf;
+pub mod error;
+pub mod exec;
+pub mod fat;
+pub mod info;
+pub mod manifest;
+pub mod pipeline;
+pub mod platform;
+pub mod preflight;
+pub mod process;
+pub mod runtime;
+pub mod sizes;
+pub mod toolchain;
+pub mod workdir;
+pub mod xar;
+
+pub use error::RecompileError;
+pub use manifest::BundleManifest;
+pub use pipeline::{compile, extract_bundle, link, parse_manifest, plan_compilation, Bundle, CompileJob, CompileOptions, CompilePlan};
+pub use process::{ElfProcess, MachOProcess};
+pub use toolchain::{Profile, ToolchainOptions};
+pub use workdir::WorkDir;
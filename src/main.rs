@@ -1,29 +1,171 @@
+extern crate bitcode_recompiler;
+#[macro_use]
+extern crate failure;
 extern crate getopts;
 extern crate memmap;
 extern crate mach_object;
+extern crate serde_json;
 
-use getopts::Options;
+use failure::Error;
+use getopts::{Matches, Options};
 use memmap::Mmap;
 use mach_object::OFile;
 
+use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
 use std::io::{Cursor};
 
-mod context;
-use context::FileContext;
-mod process;
-use process::MachOProcess;
+use bitcode_recompiler::context::FileContext;
+use bitcode_recompiler::elf;
+use bitcode_recompiler::app::{self, AppInput};
+use bitcode_recompiler::batch::{self, BatchSummary};
+use bitcode_recompiler::compare::{self, Comparison, Verdict};
+use bitcode_recompiler::info;
+use bitcode_recompiler::pipeline;
+use bitcode_recompiler::sizes::{self, SortKey};
+use bitcode_recompiler::{ElfProcess, MachOProcess, Profile, RecompileError, ToolchainOptions, WorkDir};
 
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!(
-        "Usage: {} [options] <object file> ...",
-        program
-    );
+/// Every input was handled.
+const EXIT_SUCCESS: i32 = 0;
+/// At least one input failed, the others were still handled.
+const EXIT_FAILURE: i32 = 1;
+/// The command line itself is wrong, nothing was done.
+const EXIT_USAGE: i32 = 2;
 
-    print!("{}", opts.usage(&brief));
+/// Handles one input file, returns the file written for it, if any.
+type RunFn = fn(&Invocation, &str, &[u8]) -> Result<Option<PathBuf>, Error>;
+
+/// A subcommand: its name, summary, options and what it does with each input file.
+struct Command {
+    name: &'static str,
+    summary: &'static str,
+    options: fn(&mut Options),
+    run: RunFn,
+}
+
+/// The parsed command line shared by every input of a run.
+struct Invocation {
+    matchs: Matches,
+    settings: Settings,
+    /// every input once directories and applications are expanded
+    files: Vec<PathBuf>,
+}
+
+/// How `sizes` prints its report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+/// The option values checked once before any input is handled, defaults for the options
+/// a command does not have.
+struct Settings {
+    jobs: usize,
+    timeout: Option<Duration>,
+    sort: SortKey,
+    format: Format,
+}
+
+impl Settings {
+    fn parse(matchs: &Matches) -> Result<Settings, Error> {
+        let value = |name: &str| if matchs.opt_defined(name) { matchs.opt_str(name) } else { None };
+
+        let jobs = match value("jobs") {
+            Some(jobs) => match jobs.parse::<usize>() {
+                Ok(jobs) if jobs > 0 => jobs,
+                _ => bail!("invalid job count \"{}\"", jobs),
+            },
+            None => 1,
+        };
+        let timeout = match value("timeout") {
+            Some(timeout) => match timeout.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+                _ => bail!("invalid timeout \"{}\"", timeout),
+            },
+            None => None,
+        };
+        let sort = match value("sort") {
+            Some(key) => key.parse::<SortKey>()?,
+            None => SortKey::Delta,
+        };
+        let format = match value("format").as_deref() {
+            None | Some("table") => Format::Table,
+            Some("csv") => Format::Csv,
+            Some("json") => Format::Json,
+            Some(format) => bail!("unknown format \"{}\", expected table, csv or json", format),
+        };
+
+        Ok(Settings { jobs, timeout, sort, format })
+    }
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "recompile",
+        summary: "Recompile the embedded bitcode and relink the binary",
+        options: recompile_options,
+        run: recompile,
+    },
+    Command {
+        name: "info",
+        summary: "Summarise the embedded bitcode bundle",
+        options: info_options,
+        run: print_info,
+    },
+    Command {
+        name: "extract",
+        summary: "Write every bitcode module, the TOC and a JSON manifest to a directory",
+        options: extract_options,
+        run: extract,
+    },
+    Command {
+        name: "verify",
+        summary: "Check the bundle decodes to what the TOC says, or compare a recompiled binary with the original",
+        options: verify_options,
+        run: verify,
+    },
+    Command {
+        name: "sizes",
+        summary: "Compare the size of every function of a recompiled binary with the original",
+        options: sizes_options,
+        run: sizes,
+    },
+    Command {
+        name: "hexdump",
+        summary: "Dump the __LLVM,__bundle section in hex",
+        options: no_options,
+        run: hexdump,
+    },
+    Command {
+        name: "toc",
+        summary: "Print the TOC XML of the bundle",
+        options: no_options,
+        run: print_toc,
+    },
+];
+
+fn print_commands(program: &str) {
+    println!("Usage: {} <command> [options] <file> ...", program);
+    println!();
+    println!("Commands:");
+    for command in COMMANDS {
+        println!("    {:<10}  {}", command.name, command.summary);
+    }
+    println!();
+    println!("Run `{} <command> --help` for the options of a command.", program);
+}
+
+fn report(e: &Error) {
+    eprintln!("error: {}", e);
+    for cause in e.causes().skip(1) {
+        eprintln!("  caused by: {}", cause);
+    }
 }
 
 fn main() {
@@ -35,32 +177,413 @@ fn main() {
         .to_str()
         .unwrap();
 
+    let (command, args) = match args.get(1).map(String::as_str) {
+        None | Some("help") | Some("-h") | Some("--help") => {
+            match args.get(2).and_then(|name| COMMANDS.iter().find(|command| command.name == name.as_str())) {
+                Some(command) => exit(run_command(program, command, &["--help".to_string()])),
+                None => print_commands(program),
+            }
+            exit(if args.len() > 1 { EXIT_SUCCESS } else { EXIT_USAGE });
+        },
+        // the options of the former single command line, `-f file` included
+        Some(arg) if arg.starts_with('-') => (&COMMANDS[0], &args[1..]),
+        Some(name) => match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command, &args[2..]),
+            None => {
+                eprintln!("unknown command \"{}\"", name);
+                print_commands(program);
+                exit(EXIT_USAGE);
+            },
+        },
+    };
+
+    exit(run_command(program, command, args));
+}
+
+/// Parse the options of `command` and run it on every input, returns the exit code.
+fn run_command(program: &str, command: &Command, args: &[String]) -> i32 {
     let mut opts = Options::new();
+    (command.options)(&mut opts);
+    opts.optflag("h", "help", "Print this help");
+
+    let brief = format!("Usage: {} {} [options] <file> ...\n\n{}.", program, command.name, command.summary);
+    let matchs = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprint!("{}", opts.usage(&brief));
+            return EXIT_USAGE;
+        }
+    };
+    if matchs.opt_present("help") {
+        print!("{}", opts.usage(&brief));
+        return EXIT_SUCCESS;
+    }
+    let settings = match Settings::parse(&matchs) {
+        Ok(settings) => settings,
+        Err(e) => {
+            report(&e);
+            eprint!("{}", opts.usage(&brief));
+            return EXIT_USAGE;
+        }
+    };
+
+    let paths = inputs(&matchs);
+    if paths.is_empty() {
+        eprintln!("at least one file must be specified");
+        eprint!("{}", opts.usage(&brief));
+        return EXIT_USAGE;
+    }
+    let (files, apps) = match collect_inputs(&paths) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            report(&e);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let invocation = Invocation { matchs, settings, files };
+    let mut summary = BatchSummary::new();
+    let mut outputs = HashMap::new();
+    for file in &invocation.files {
+        // every binary starts from a fresh state, a failure does not stop the batch
+        let result = run_file(command, &invocation, &file.to_string_lossy());
+        match result {
+            Ok(Some(ref output)) => {
+                outputs.insert(file.clone(), output.clone());
+            },
+            Err(ref e) => report(e),
+            _ => {},
+        }
+        summary.push(file, &result.map(|_| ()));
+    }
+    if invocation.files.len() > 1 {
+        println!();
+        print!("{}", summary);
+    }
+
+    let mut status = if summary.failed() > 0 { EXIT_FAILURE } else { EXIT_SUCCESS };
+    if invocation.matchs.opt_defined("ipa-output") {
+        if let Some(dir) = invocation.matchs.opt_str("ipa-output") {
+            for app in &apps {
+                if let Err(e) = write_ipa(app, &outputs, Path::new(&dir)) {
+                    report(&e);
+                    status = EXIT_FAILURE;
+                }
+            }
+        }
+    }
+    status
+}
+
+/// Expand `.ipa`, `.xcarchive` and `.app` inputs into their binaries and other
+/// directories into the Mach-O files they hold.
+fn collect_inputs(paths: &[String]) -> Result<(Vec<PathBuf>, Vec<AppInput>), Error> {
+    let mut files = Vec::new();
+    let mut apps = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if app::is_ipa(path) || app::is_xcarchive(path) || app::is_app(path) {
+            let app = AppInput::open(path)?;
+            files.extend(app.binaries.iter().cloned());
+            apps.push(app);
+        } else {
+            files.append(&mut batch::collect_inputs(&[path])?);
+        }
+    }
+    Ok((files, apps))
+}
+
+/// Write `<dir>/<app>.ipa` with the binaries of `app` replaced by their recompiled files.
+fn write_ipa(app: &AppInput, outputs: &HashMap<PathBuf, PathBuf>, dir: &Path) -> Result<(), Error> {
+    let mut replaced = HashMap::new();
+    for binary in &app.binaries {
+        match outputs.get(binary) {
+            Some(output) => {
+                replaced.insert(binary.clone(), output.clone());
+            },
+            None => eprintln!("warning: {} is kept as it is in the new ipa", binary.display()),
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    let output = dir.join(format!("{}.ipa", app.name()));
+    app.write_ipa(&replaced, &output)?;
+    println!("ipa {} created from {}.", output.display(), app.source.display());
+    Ok(())
+}
+
+/// The positional inputs, plus the `-f` ones of the former command line. Directories
+/// are expanded by `batch::collect_inputs`.
+fn inputs(matchs: &Matches) -> Vec<String> {
+    let mut files = matchs.free.clone();
+    if matchs.opt_defined("file") {
+        files.extend(matchs.opt_strs("file"));
+    }
+    files
+}
+
+fn map_file(path: &Path) -> Result<Mmap, Error> {
+    let file = fs::File::open(path)
+        .map_err(|e| format_err!("cannot open {}: {}", path.display(), e))?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(mmap)
+}
+
+fn run_file(command: &Command, invocation: &Invocation, file_name: &str) -> Result<Option<PathBuf>, Error> {
+    let mmap = map_file(Path::new(file_name))?;
+    (command.run)(invocation, file_name, mmap.as_ref())
+}
+
+fn no_options(_: &mut Options) {}
+
+fn recompile_options(opts: &mut Options) {
     opts.optopt("", "sdk", "Specifies the sdk path", "sdk_path");
     opts.optopt("", "tool", "Specifies the ToolChain path", "tool_chain");
-    opts.optopt("f", "file", "Specifies the input file", "");
+    opts.optflag("", "cross", "Rebuild without Xcode: an upstream clang, ld64.lld and an SDK copied from a Mac given by --sdk or SDKROOT");
+    opts.optopt("", "clang", "The clang compiling the modules, usr/bin/clang of the ToolChain by default, clang of PATH with --cross", "path");
+    opts.optopt("", "swift", "The Swift frontend, usr/bin/swift-frontend of the ToolChain by default, swift of PATH with --cross", "path");
+    opts.optopt("", "ld", "The linker, usr/bin/ld of the ToolChain by default, ld64.lld of PATH with --cross", "path");
+    opts.optmulti("f", "file", "Specifies the input file", "file");
+    opts.optflag("", "universal", "Merge recompiled slices of a fat file into a universal binary");
+    opts.optopt("", "work-dir", "Directory for the intermediate files, a unique temp dir by default", "dir");
+    opts.optflag("", "keep-work-dir", "Keep the intermediate files after the run");
+    opts.optopt("j", "jobs", "Number of modules compiled in parallel, 1 by default", "N");
+    opts.optopt("", "timeout", "Kill a compiler running longer than the given seconds", "seconds");
+    opts.optopt("", "ipa-output", "Write every .ipa, .xcarchive or .app input as an .ipa with the recompiled binaries into dir", "dir");
+    opts.optflag("", "verify", "Compare every recompiled binary with its original, an incompatible one fails");
+    opts.optflag("", "force", "Compile even when the compiler or SDK does not match the one that built the bitcode");
+}
 
-    let matchs = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(_) => {
-            print_usage(&program, opts);
+fn recompile(invocation: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    let matchs = &invocation.matchs;
+    let settings = &invocation.settings;
 
-            exit(-1);
+    let work_dir_path = matchs.opt_str("work-dir");
+    let work_dir = WorkDir::new(work_dir_path.as_ref().map(Path::new), matchs.opt_present("keep-work-dir"))?;
+    if matchs.opt_present("keep-work-dir") {
+        println!("intermediate files are kept in {}", work_dir.path().display());
+    }
+
+    let toolchain = ToolchainOptions {
+        profile: if matchs.opt_present("cross") { Profile::Cross } else { Profile::Xcode },
+        sdk: matchs.opt_str("sdk"),
+        tool_chain: matchs.opt_str("tool"),
+        clang: matchs.opt_str("clang"),
+        swift: matchs.opt_str("swift"),
+        ld: matchs.opt_str("ld"),
+    };
+
+    if elf::is_elf(payload) {
+        let mut elf_process = ElfProcess::new();
+        elf_process.set_input(file_name);
+        if let Some(ref clang) = toolchain.clang {
+            elf_process.set_clang(Path::new(clang));
+        }
+        elf_process.set_work_dir(work_dir.path());
+        elf_process.set_timeout(settings.timeout);
+        elf_process.handle_elf(payload)?;
+        if matchs.opt_present("verify") {
+            eprintln!("warning: {} is not verified, only Mach-O files are compared", file_name);
+        }
+        return Ok(None);
+    }
+
+    let mut cur = Cursor::new(payload);
+    let ofile = OFile::parse(&mut cur)?;
+    let mut filectx = FileContext::new(payload);
+
+    let mut mach_process = MachOProcess::new();
+    mach_process.set_input(file_name);
+    mach_process.set_universal(matchs.opt_present("universal"));
+    mach_process.set_force(matchs.opt_present("force"));
+    mach_process.set_work_dir(work_dir.path());
+    mach_process.set_jobs(settings.jobs);
+    mach_process.set_timeout(settings.timeout);
+    mach_process.handle_ofile(&ofile, &mut filectx, &toolchain)?;
+    let output = mach_process.output().map(PathBuf::from);
+    if matchs.opt_present("verify") {
+        // the slices of a fat file that are not merged are compared one by one
+        let recompiled = match output {
+            Some(ref output) => vec![output.clone()],
+            None => mach_process.slices().iter().map(|(_, path)| PathBuf::from(path)).collect(),
+        };
+        if recompiled.is_empty() {
+            eprintln!("warning: {} is not verified, nothing was written for it", file_name);
+        }
+        let mut incompatible = Vec::new();
+        for path in &recompiled {
+            if let Err(e) = check_compatible(&compare_files(file_name, payload, path)?, false) {
+                incompatible.push(e.to_string());
+            }
         }
+        if !incompatible.is_empty() {
+            bail!("{}", incompatible.join("\n"));
+        }
+    }
+    Ok(output)
+}
+
+/// The Mach-O file of `payload`, the bundle commands do not apply to ELF files.
+fn parse_macho(file_name: &str, payload: &[u8]) -> Result<OFile, Error> {
+    if elf::is_elf(payload) {
+        bail!(RecompileError::MissingBundle(file_name.to_string()));
+    }
+    let ofile = OFile::parse(&mut Cursor::new(payload))?;
+    Ok(ofile)
+}
+
+/// The bundles of every slice, failing when there is none at all.
+fn bundles(file_name: &str, payload: &[u8]) -> Result<Vec<(String, pipeline::Bundle)>, Error> {
+    let ofile = parse_macho(file_name, payload)?;
+    let bundles = pipeline::find_bundles(&ofile, payload)?
+        .into_iter()
+        .filter_map(|(arch, bundle)| bundle.map(|bundle| (arch, bundle)))
+        .collect::<Vec<_>>();
+    if bundles.is_empty() {
+        bail!(RecompileError::MissingBundle(file_name.to_string()));
+    }
+    Ok(bundles)
+}
+
+fn extract_options(opts: &mut Options) {
+    opts.optopt("o", "output", "Directory the modules are written to, <binary>.bitcode by default. \
+                                With several inputs each one goes to a sub directory named after it", "dir");
+}
+
+fn extract(invocation: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    let matchs = &invocation.matchs;
+    let output = match matchs.opt_str("output") {
+        Some(ref dir) if invocation.files.len() > 1 => {
+            Path::new(dir).join(Path::new(file_name).file_name().unwrap_or_default())
+        },
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(format!("{}.bitcode", file_name)),
     };
 
+    let ofile = parse_macho(file_name, payload)?;
+    let mut filectx = FileContext::new(payload);
+
     let mut mach_process = MachOProcess::new();
-    if let Some(file_name) = matchs.opt_str("file") {
-        let file = fs::File::open(file_name).unwrap();
-        let mmap = unsafe { Mmap::map(&file).unwrap() };
-        let payload: &[u8] = mmap.as_ref();
-        let mut cur = Cursor::new(payload);
-        let ofile = OFile::parse(&mut cur).unwrap();
-        let mut filectx = FileContext::new(payload);
-
-        mach_process.handle_ofile(&ofile, &mut filectx, matchs.opt_str("sdk"), matchs.opt_str("tool"));
+    mach_process.set_input(file_name);
+    mach_process.extract_ofile(&ofile, &mut filectx, &output)?;
+    Ok(Some(output))
+}
+
+fn info_options(opts: &mut Options) {
+    opts.optflag("", "json", "Print the summary as JSON");
+}
+
+fn print_info(invocation: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    let matchs = &invocation.matchs;
+    let file_info = info::inspect(file_name, payload)?;
+    if matchs.opt_present("json") {
+        println!("{}", serde_json::to_string_pretty(&file_info)?);
+    } else {
+        print!("{}", file_info);
+    }
+    Ok(None)
+}
+
+fn verify_options(opts: &mut Options) {
+    opts.optopt("", "against", "Compare the recompiled binary file with the input instead. \
+                                With several inputs file is a directory holding them under the same names", "file");
+    opts.optflag("", "json", "Print the comparison as JSON");
+}
+
+/// Compare the recompiled file `output` with the original `file_name`.
+fn compare_files(file_name: &str, payload: &[u8], output: &Path) -> Result<Comparison, Error> {
+    let mmap = map_file(output)?;
+    compare::compare(file_name, payload, &output.to_string_lossy(), mmap.as_ref())
+}
+
+/// The recompiled file `--against` gives for the input `file_name`, a directory
+/// holding it under the same name when there are several inputs.
+fn against(invocation: &Invocation, file_name: &str) -> Option<PathBuf> {
+    let against = invocation.matchs.opt_str("against")?;
+    if invocation.files.len() > 1 {
+        Some(Path::new(&against).join(Path::new(file_name).file_name().unwrap_or_default()))
+    } else {
+        Some(PathBuf::from(against))
+    }
+}
+
+/// Print `comparison`, failing when the recompiled file cannot replace the original.
+fn check_compatible(comparison: &Comparison, json: bool) -> Result<(), Error> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(comparison)?);
     } else {
-        println!("at least one file must be specified");
-        print_usage(&program, opts);
+        print!("{}", comparison);
+    }
+    if comparison.verdict == Verdict::Incompatible {
+        bail!("{} is incompatible with {}", comparison.recompiled, comparison.original);
+    }
+    Ok(())
+}
+
+fn verify(invocation: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    if let Some(recompiled) = against(invocation, file_name) {
+        check_compatible(&compare_files(file_name, payload, &recompiled)?, invocation.matchs.opt_present("json"))?;
+        return Ok(None);
+    }
+
+    for (arch, bundle) in bundles(file_name, payload)? {
+        let manifest = pipeline::check_bundle(&bundle)?;
+        println!("{} ({}): {} modules ok", file_name, arch, manifest.files.len());
+    }
+    Ok(None)
+}
+
+fn sizes_options(opts: &mut Options) {
+    opts.optopt("", "against", "The recompiled binary file. With several inputs file is a directory \
+                                holding them under the same names", "file");
+    opts.optopt("", "format", "Output as table, csv or json, table by default", "format");
+    opts.optopt("", "sort", "Order by name, section, original, recompiled or delta (the largest change first), \
+                             delta by default", "key");
+    opts.optflag("", "all", "List the functions whose size did not change too");
+}
+
+fn sizes(invocation: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    let matchs = &invocation.matchs;
+    let recompiled = match against(invocation, file_name) {
+        Some(recompiled) => recompiled,
+        None => bail!("--against is required"),
+    };
+
+    let mmap = map_file(&recompiled)?;
+    let mut report = sizes::compare_sizes(file_name, payload, &recompiled.to_string_lossy(), mmap.as_ref())?;
+    for slice in &mut report.slices {
+        if !matchs.opt_present("all") {
+            slice.retain_changed();
+        }
+        slice.sort(invocation.settings.sort);
+    }
+
+    match invocation.settings.format {
+        Format::Table => print!("{}", report),
+        Format::Csv => print!("{}", report.to_csv()),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(None)
+}
+
+fn hexdump(_: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    for (arch, bundle) in bundles(file_name, payload)? {
+        println!("{} ({}): ({},{}) section", file_name, arch, pipeline::SEGMENT_NAME, pipeline::SECTION_NAME);
+        let dump = FileContext::new(&bundle.data).hexdump(bundle.offset as usize, bundle.data.len())?;
+        print!("{}", String::from_utf8_lossy(&dump));
+    }
+    Ok(None)
+}
+
+fn print_toc(_: &Invocation, file_name: &str, payload: &[u8]) -> Result<Option<PathBuf>, Error> {
+    let bundles = bundles(file_name, payload)?;
+    let several = bundles.len() > 1;
+    for (arch, bundle) in bundles {
+        if several {
+            println!("<!-- {} -->", arch);
+        }
+        println!("{}", bundle.archive()?.toc);
     }
+    Ok(None)
 }
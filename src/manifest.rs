@@ -0,0 +1,400 @@
+extern crate failure;
+extern crate serde;
+extern crate xml;
+
+use self::failure::Error;
+use self::serde::{Serialize, Serializer};
+use self::xml::attribute::OwnedAttribute;
+use self::xml::reader::{EventReader, XmlEvent};
+
+use error::RecompileError;
+
+/// What a module of the bundle holds, from `<file-type>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileType {
+    Bitcode,
+    Object,
+    Bundle,
+    Lto,
+    Other(String),
+}
+
+impl FileType {
+    fn parse(s: &str) -> FileType {
+        match s {
+            "Bitcode" => FileType::Bitcode,
+            "Object" => FileType::Object,
+            "Bundle" => FileType::Bundle,
+            "LTO" => FileType::Lto,
+            _ => FileType::Other(s.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            FileType::Bitcode => "Bitcode",
+            FileType::Object => "Object",
+            FileType::Bundle => "Bundle",
+            FileType::Lto => "LTO",
+            FileType::Other(ref s) => s,
+        }
+    }
+}
+
+impl Serialize for FileType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Checksum {
+    pub style: String,
+    pub value: String,
+}
+
+/// Where the checksum of the TOC itself is stored in the heap.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TocChecksum {
+    pub style: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A dylib the binary was linked against, from `<dylibs>`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Dylib {
+    pub path: String,
+    /// listed as `<weak>` rather than `<lib>`
+    pub weak: bool,
+}
+
+impl Dylib {
+    /// The framework name to link with, `None` for system libraries under `/usr/lib`.
+    pub fn framework(&self) -> Option<&str> {
+        if self.path.contains("/usr/lib") {
+            return None;
+        }
+        let off = match self.path.rfind('/') {
+            Some(index) => index + 1,
+            None => 0,
+        };
+        Some(&self.path[off..])
+    }
+}
+
+/// A `<file>` of the bundle TOC.
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleFile {
+    pub id: String,
+    pub name: String,
+    pub file_type: FileType,
+    /// `clang -cc1` arguments from `<clang><cmd>`
+    pub clang: Vec<String>,
+    /// `swift -frontend` arguments from `<swift><cmd>`
+    pub swift: Vec<String>,
+    /// offset of the contents in the xar heap
+    pub offset: u64,
+    /// number of bytes in the heap
+    pub length: u64,
+    /// number of bytes once extracted
+    pub size: u64,
+    pub encoding: String,
+    pub archived_checksum: Option<Checksum>,
+    pub extracted_checksum: Option<Checksum>,
+}
+
+impl BundleFile {
+    fn new(id: String) -> BundleFile {
+        BundleFile {
+            id,
+            name: String::new(),
+            file_type: FileType::Other(String::new()),
+            clang: Vec::new(),
+            swift: Vec::new(),
+            offset: 0,
+            length: 0,
+            size: 0,
+            encoding: String::new(),
+            archived_checksum: None,
+            extracted_checksum: None,
+        }
+    }
+
+    pub fn is_swift(&self) -> bool {
+        !self.swift.is_empty()
+    }
+}
+
+/// The bundle TOC written by ld64: the `Ld` subdoc plus every file entry.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BundleManifest {
+    pub version: String,
+    pub architecture: String,
+    pub platform: String,
+    pub sdkversion: String,
+    pub hide_symbols: bool,
+    pub dylibs: Vec<Dylib>,
+    pub link_options: Vec<String>,
+    pub toc_checksum: Option<TocChecksum>,
+    pub creation_time: String,
+    pub files: Vec<BundleFile>,
+}
+
+fn attribute(attributes: &[OwnedAttribute], name: &str) -> Option<String> {
+    attributes.iter()
+        .find(|attr| attr.name.local_name == name)
+        .map(|attr| attr.value.clone())
+}
+
+fn parse_number(path: &[String], text: &str) -> Result<u64, Error> {
+    text.trim()
+        .parse()
+        .map_err(|_| RecompileError::MalformedXar(format!("bad {} \"{}\"", path.join("/"), text)).into())
+}
+
+impl BundleManifest {
+    /// Parse the TOC of a bitcode bundle, see `XarArchive::toc`.
+    pub fn parse(toc: &str) -> Result<BundleManifest, Error> {
+        let mut manifest = BundleManifest::default();
+        // element names from the root to the current element
+        let mut path: Vec<String> = Vec::new();
+        // style of the checksum element being read
+        let mut style = String::new();
+
+        let mut parse = EventReader::new(toc.as_bytes());
+        loop {
+            let event = parse.next()
+                .map_err(|e| RecompileError::MalformedXar(format!("bad xar toc, {}", e)))?;
+            match event {
+                XmlEvent::EndDocument => break,
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let local_name = name.local_name;
+                    let in_toc = path.len() == 2 && path[1] == "toc";
+                    match local_name.as_str() {
+                        "file" if in_toc => {
+                            manifest.files.push(BundleFile::new(attribute(&attributes, "id").unwrap_or_default()));
+                        },
+                        "hide-symbols" => manifest.hide_symbols = true,
+                        "encoding" => {
+                            if let Some(file) = manifest.files.last_mut() {
+                                file.encoding = attribute(&attributes, "style").unwrap_or_default();
+                            }
+                        },
+                        _ => {},
+                    }
+                    if local_name.ends_with("checksum") {
+                        style = attribute(&attributes, "style").unwrap_or_default();
+                        if local_name == "checksum" && in_toc {
+                            manifest.toc_checksum = Some(TocChecksum {
+                                style: style.clone(),
+                                offset: 0,
+                                size: 0,
+                            });
+                        }
+                    }
+                    path.push(local_name);
+                },
+                XmlEvent::Characters(text) => {
+                    manifest.parse_text(&path, text, &style)?;
+                },
+                XmlEvent::EndElement { .. } => {
+                    path.pop();
+                },
+                _ => {},
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    fn parse_text(&mut self, path: &[String], text: String, style: &str) -> Result<(), Error> {
+        // drop the leading "xar"
+        let names = path.iter().skip(1).map(|s| s.as_str()).collect::<Vec<_>>();
+
+        match names.as_slice() {
+            ["subdoc", "version"] => self.version = text,
+            ["subdoc", "architecture"] => self.architecture = text,
+            ["subdoc", "platform"] => self.platform = text,
+            ["subdoc", "sdkversion"] => self.sdkversion = text,
+            ["subdoc", "dylibs", "lib"] => self.dylibs.push(Dylib { path: text, weak: false }),
+            ["subdoc", "dylibs", "weak"] => self.dylibs.push(Dylib { path: text, weak: true }),
+            ["subdoc", "link-options", "option"] => self.link_options.push(text),
+            ["toc", "creation-time"] => self.creation_time = text,
+            ["toc", "checksum", "offset"] => {
+                if let Some(ref mut checksum) = self.toc_checksum {
+                    checksum.offset = parse_number(path, &text)?;
+                }
+            },
+            ["toc", "checksum", "size"] => {
+                if let Some(ref mut checksum) = self.toc_checksum {
+                    checksum.size = parse_number(path, &text)?;
+                }
+            },
+            ["toc", "file", rest @ ..] => {
+                let file = match self.files.last_mut() {
+                    Some(file) => file,
+                    None => return Ok(()),
+                };
+                match rest {
+                    ["name"] => file.name = text,
+                    ["file-type"] => file.file_type = FileType::parse(text.trim()),
+                    ["clang", "cmd"] => file.clang.push(text),
+                    ["swift", "cmd"] => file.swift.push(text),
+                    ["data", "offset"] => file.offset = parse_number(path, &text)?,
+                    ["data", "length"] => file.length = parse_number(path, &text)?,
+                    ["data", "size"] => file.size = parse_number(path, &text)?,
+                    ["data", "archived-checksum"] => {
+                        file.archived_checksum = Some(Checksum { style: style.to_string(), value: text });
+                    },
+                    ["data", "extracted-checksum"] => {
+                        file.extracted_checksum = Some(Checksum { style: style.to_string(), value: text });
+                    },
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Dylibs to link as `-framework`/`-weak_framework`.
+    pub fn frameworks(&self) -> Vec<&Dylib> {
+        self.dylibs.iter().filter(|dylib| dylib.framework().is_some()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The TOC ld64 writes into `__LLVM,__bundle`, trimmed to two modules.
+    const TOC: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar>
+ <subdoc subdoc_name="Ld">
+  <version>1.0</version>
+  <architecture>arm64</architecture>
+  <platform>iPhoneOS</platform>
+  <sdkversion>12.1.0</sdkversion>
+  <hide-symbols>1</hide-symbols>
+  <dylibs>
+   <lib>{SDKPATH}/System/Library/Frameworks/Foundation.framework/Foundation</lib>
+   <weak>{SDKPATH}/System/Library/Frameworks/UIKit.framework/UIKit</weak>
+   <lib>{SDKPATH}/usr/lib/libSystem.B.dylib</lib>
+  </dylibs>
+  <link-options>
+   <option>-execute</option>
+   <option>-ios_version_min</option>
+   <option>9.0.0</option>
+  </link-options>
+ </subdoc>
+ <toc>
+  <checksum style="sha1">
+   <offset>0</offset>
+   <size>20</size>
+  </checksum>
+  <creation-time>2018-12-01T10:00:00</creation-time>
+  <file id="1">
+   <name>1</name>
+   <type>file</type>
+   <data>
+    <archived-checksum style="sha1">0123456789abcdef0123456789abcdef01234567</archived-checksum>
+    <extracted-checksum style="sha1">89abcdef0123456789abcdef0123456789abcdef</extracted-checksum>
+    <encoding style="application/octet-stream"/>
+    <size>1024</size>
+    <offset>20</offset>
+    <length>1024</length>
+   </data>
+   <file-type>Bitcode</file-type>
+   <clang>
+    <cmd>-triple</cmd>
+    <cmd>arm64-apple-ios9.0.0</cmd>
+    <cmd>-O2</cmd>
+   </clang>
+  </file>
+  <file id="2">
+   <name>2</name>
+   <type>file</type>
+   <data>
+    <encoding style="application/x-gzip"/>
+    <size>2048</size>
+    <offset>1044</offset>
+    <length>512</length>
+   </data>
+   <file-type>Bitcode</file-type>
+   <swift>
+    <cmd>-frontend</cmd>
+    <cmd>-c</cmd>
+   </swift>
+  </file>
+ </toc>
+</xar>
+"#;
+
+    #[test]
+    fn linker_subdoc() {
+        let manifest = BundleManifest::parse(TOC).unwrap();
+        assert_eq!(manifest.version, "1.0");
+        assert_eq!(manifest.architecture, "arm64");
+        assert_eq!(manifest.platform, "iPhoneOS");
+        assert_eq!(manifest.sdkversion, "12.1.0");
+        assert!(manifest.hide_symbols);
+        assert_eq!(manifest.link_options, vec!["-execute", "-ios_version_min", "9.0.0"]);
+
+        let dylibs = manifest.dylibs.iter().map(|dylib| (dylib.framework(), dylib.weak)).collect::<Vec<_>>();
+        assert_eq!(dylibs, vec![(Some("Foundation"), false), (Some("UIKit"), true), (None, false)]);
+        assert_eq!(manifest.frameworks().len(), 2);
+    }
+
+    #[test]
+    fn toc_checksum() {
+        let manifest = BundleManifest::parse(TOC).unwrap();
+        assert_eq!(manifest.toc_checksum, Some(TocChecksum { style: "sha1".to_string(), offset: 0, size: 20 }));
+        assert_eq!(manifest.creation_time, "2018-12-01T10:00:00");
+    }
+
+    #[test]
+    fn files() {
+        let manifest = BundleManifest::parse(TOC).unwrap();
+        assert_eq!(manifest.files.len(), 2);
+
+        let clang = &manifest.files[0];
+        assert_eq!((clang.id.as_str(), clang.name.as_str()), ("1", "1"));
+        assert_eq!(clang.file_type, FileType::Bitcode);
+        assert_eq!(clang.clang, vec!["-triple", "arm64-apple-ios9.0.0", "-O2"]);
+        assert!(!clang.is_swift());
+        assert_eq!((clang.offset, clang.length, clang.size), (20, 1024, 1024));
+        assert_eq!(clang.encoding, "application/octet-stream");
+        assert_eq!(clang.archived_checksum, Some(Checksum {
+            style: "sha1".to_string(),
+            value: "0123456789abcdef0123456789abcdef01234567".to_string(),
+        }));
+        assert_eq!(clang.extracted_checksum.as_ref().map(|checksum| checksum.value.as_str()),
+                   Some("89abcdef0123456789abcdef0123456789abcdef"));
+
+        let swift = &manifest.files[1];
+        assert!(swift.is_swift());
+        assert!(swift.clang.is_empty());
+        assert_eq!(swift.swift, vec!["-frontend", "-c"]);
+        assert_eq!((swift.offset, swift.length, swift.size), (1044, 512, 2048));
+        assert_eq!(swift.encoding, "application/x-gzip");
+        assert_eq!(swift.archived_checksum, None);
+    }
+
+    #[test]
+    fn file_types() {
+        for name in &["Bitcode", "Object", "Bundle", "LTO", "Assembly"] {
+            assert_eq!(FileType::parse(name).as_str(), *name);
+        }
+        assert_eq!(FileType::parse("LTO"), FileType::Lto);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        let toc = TOC.replace("<size>2048</size>", "<size>big</size>");
+        let err = BundleManifest::parse(&toc).unwrap_err();
+        assert!(err.to_string().contains("toc/file/data/size"), "{}", err);
+        assert!(BundleManifest::parse("<xar><toc>").is_err());
+    }
+}
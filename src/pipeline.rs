@@ -0,0 +1,471 @@
+//! The recompilation of a bitcode bundle as separate stages:
+//! `extract_bundle` -> `parse_manifest` -> `plan_compilation` -> `compile` -> `link`.
+
+extern crate failure;
+extern crate mach_object;
+extern crate serde_json;
+
+use self::failure::Error;
+use mach_object::{LoadCommand, MachCommand, OFile, Section};
+
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcode::{self, ModuleHeader};
+use context::{FileContext, ReCompilerInfo};
+use error::{ModuleFailure, ModuleFailures, RecompileError};
+use exec;
+use fat;
+use manifest::{BundleFile, BundleManifest, FileType};
+use platform;
+use toolchain::{Profile, ToolchainOptions, Tools};
+use xar::XarArchive;
+
+pub const SEGMENT_NAME: &str = "__LLVM";
+pub const SECTION_NAME: &str = "__bundle";
+
+const MH_MAGIC_BYTES: &[u8] = b"\xce\xfa\xed\xfe";
+const MH_MAGIC_64_BYTES: &[u8] = b"\xcf\xfa\xed\xfe";
+
+pub fn find_section(commands: &[MachCommand], segname: &str, sectname: &str) -> Option<Rc<Section>> {
+    for cmd in commands {
+        match *cmd.command() {
+            LoadCommand::Segment {ref sections, ..} | LoadCommand::Segment64 {ref sections, ..} => {
+                for sect in sections {
+                    if sect.segname == segname && sect.sectname == sectname {
+                        return Some(sect.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The xar archive held by the `__LLVM,__bundle` section.
+pub struct Bundle {
+    /// file offset of the section
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl Bundle {
+    pub fn archive(&self) -> Result<XarArchive<'_>, Error> {
+        XarArchive::parse(&self.data)
+    }
+}
+
+/// The compiler a module of the bundle is rebuilt with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frontend {
+    /// `clang -cc1`
+    Clang,
+    /// `swift -frontend -c`
+    Swift,
+}
+
+impl Frontend {
+    fn command(self, tools: &Tools) -> Command {
+        let mut command;
+        match self {
+            Frontend::Clang => {
+                command = Command::new(&tools.clang);
+                command.arg("-cc1");
+            },
+            Frontend::Swift => {
+                command = Command::new(&tools.swift);
+                command.args(["-frontend", "-c"]);
+            },
+        }
+        command
+    }
+}
+
+/// One compiler invocation of the plan.
+#[derive(Debug, Clone)]
+pub struct CompileJob {
+    /// name of the module in the bundle
+    pub name: String,
+    /// `None` for modules that are already objects and are linked as they are
+    pub frontend: Option<Frontend>,
+    pub args: Vec<String>,
+    /// object file produced by the job
+    pub output: String,
+}
+
+pub struct CompilePlan {
+    pub manifest: BundleManifest,
+    /// sdk and ToolChain resolved for the bundle platform
+    pub toolchain: ReCompilerInfo,
+    pub jobs: Vec<CompileJob>,
+    /// the rebuilt binary, taken from `-executable_path`
+    pub output: Option<String>,
+}
+
+/// Read the `__LLVM,__bundle` section of a thin Mach-O file, `None` when there is no bundle.
+pub fn extract_bundle(commands: &[MachCommand], payload: &[u8]) -> Result<Option<Bundle>, Error> {
+    let sect = match find_section(commands, SEGMENT_NAME, SECTION_NAME) {
+        Some(sect) => sect,
+        None => return Ok(None),
+    };
+
+    let mut ctxt = FileContext::new(payload);
+    ctxt.cur.seek(SeekFrom::Start(u64::from(sect.offset)))?;
+
+    let bundle = Bundle {
+        offset: u64::from(sect.offset),
+        data: ctxt.section_hex(sect.addr, sect.size)?,
+    };
+    // reject a bundle that is not a xar archive right away
+    bundle.archive()?;
+
+    Ok(Some(bundle))
+}
+
+/// The bundle of every thin file or fat slice of `ofile`, with the architecture it belongs to.
+pub fn find_bundles(ofile: &OFile, payload: &[u8]) -> Result<Vec<(String, Option<Bundle>)>, Error> {
+    let mut bundles = Vec::new();
+
+    match *ofile {
+        OFile::MachFile { ref header, ref commands } => {
+            bundles.push((fat::cpu_name(header.cputype, header.cpusubtype), extract_bundle(commands, payload)?));
+        },
+        OFile::FatFile { ref files, .. } => {
+            for (arch, file) in files {
+                let start = arch.offset as usize;
+                let end = start + arch.size as usize;
+                if end > payload.len() {
+                    bail!(RecompileError::malformed("fat file", format!("slice {} exceeds the file", fat::arch_name(arch))));
+                }
+                bundles.append(&mut find_bundles(file, &payload[start..end])?);
+            }
+        },
+        _ => {},
+    }
+
+    Ok(bundles)
+}
+
+/// Decode every module of the bundle and check it holds what the TOC says it does.
+pub fn check_bundle(bundle: &Bundle) -> Result<BundleManifest, Error> {
+    let archive = bundle.archive()?;
+    let manifest = BundleManifest::parse(&archive.toc)?;
+
+    for file in &manifest.files {
+        let entry = match archive.find(&file.name) {
+            Some(entry) => entry,
+            None => bail!(RecompileError::MalformedXar(format!("module {} is not in the archive", file.name))),
+        };
+        let data = archive.extract(entry)?;
+        let magic = data.get(..4).unwrap_or(&[]);
+        let expected = match file.file_type {
+            FileType::Bitcode | FileType::Lto => bitcode::is_bitcode(&data),
+            FileType::Object => magic == MH_MAGIC_64_BYTES || magic == MH_MAGIC_BYTES,
+            _ => true,
+        };
+        if !expected {
+            bail!(RecompileError::MalformedXar(format!("module {} is not a {} file", file.name, file.file_type.as_str())));
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// The headers of the bitcode modules of a bundle.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleHeaders {
+    /// the wrapper, producer and epoch of every module, by module name
+    pub headers: Vec<(String, ModuleHeader)>,
+    /// why the header of the other modules could not be read
+    pub unreadable: Vec<String>,
+}
+
+/// Read the header of every bitcode module of the bundle, a module that cannot be read
+/// is recorded rather than failing the others.
+pub fn module_headers(bundle: &Bundle, manifest: &BundleManifest) -> Result<ModuleHeaders, Error> {
+    let archive = bundle.archive()?;
+    let mut headers = ModuleHeaders::default();
+
+    for file in &manifest.files {
+        if file.file_type != FileType::Bitcode && file.file_type != FileType::Lto {
+            continue;
+        }
+        let header = match archive.find(&file.name) {
+            Some(entry) => archive.extract(entry).and_then(|data| ModuleHeader::parse(&data)),
+            None => Err(RecompileError::MalformedXar(format!("module {} is not in the archive", file.name)).into()),
+        };
+        match header {
+            Ok(header) => headers.headers.push((file.name.clone(), header)),
+            Err(e) => headers.unreadable.push(format!("module {}: {}", file.name, e)),
+        }
+    }
+
+    Ok(headers)
+}
+
+/// Parse the TOC of the bundle into a typed manifest.
+pub fn parse_manifest(bundle: &Bundle) -> Result<BundleManifest, Error> {
+    BundleManifest::parse(&bundle.archive()?.toc)
+}
+
+fn compile_job(file: &BundleFile) -> CompileJob {
+    let name = file.name.clone();
+
+    if file.file_type == FileType::Object {
+        return CompileJob {
+            output: name.clone(),
+            name,
+            frontend: None,
+            args: Vec::new(),
+        };
+    }
+
+    let output = format!("{}.o", name);
+    let (frontend, args) = if file.is_swift() {
+        let mut args = file.swift.clone();
+        args.extend(vec![name.clone(), "-o".to_string(), output.clone()]);
+        (Frontend::Swift, args)
+    } else {
+        let mut args = vec![name.clone()];
+        args.extend(file.clang.iter().cloned());
+        args.extend(vec!["-x".to_string(), "ir".to_string(), "-o".to_string(), output.clone()]);
+        (Frontend::Clang, args)
+    };
+
+    CompileJob {
+        name,
+        frontend: Some(frontend),
+        args,
+        output,
+    }
+}
+
+/// Resolve the sdk, ToolChain and tools for the bundle platform and lay out the compile jobs.
+pub fn plan_compilation(manifest: BundleManifest, options: &ToolchainOptions) -> Result<CompilePlan, Error> {
+    let mut toolchain = ReCompilerInfo::new();
+    toolchain.set_platform(manifest.platform.clone());
+    toolchain.choose_path_from_platform(options)?;
+
+    let mut jobs = manifest.files.iter().map(compile_job).collect::<Vec<_>>();
+    // cc1 targets the host without a triple, give it the one of the bundle platform
+    if let Some(platform) = platform::find(&manifest.platform) {
+        let triple = platform.triple(&manifest.architecture, &deployment_target(&manifest, &jobs).unwrap_or_default());
+        for job in &mut jobs {
+            if job.frontend == Some(Frontend::Clang) && !job.args.iter().any(|arg| arg.as_str() == "-triple") {
+                job.args.splice(1..1, vec!["-triple".to_string(), triple.clone()]);
+            }
+        }
+    }
+
+    let output = manifest.link_options.iter()
+        .skip_while(|opt| opt.as_str() != "-executable_path")
+        .nth(1)
+        .map(|path| {
+            let off = match path.rfind('/') {
+                Some(index) => index + 1,
+                None => 0,
+            };
+            format!("./{}", &path[off..])
+        });
+
+    Ok(CompilePlan {
+        manifest,
+        toolchain,
+        jobs,
+        output,
+    })
+}
+
+/// File names `extract_to` writes next to the modules.
+pub const TOC_FILE: &str = "toc.xml";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Write every module of the bundle into `dir` under its TOC name, together with the
+/// TOC itself and the parsed manifest as JSON. No sdk or ToolChain is involved.
+pub fn extract_to(bundle: &Bundle, dir: &Path) -> Result<BundleManifest, Error> {
+    let archive = bundle.archive()?;
+    let manifest = BundleManifest::parse(&archive.toc)?;
+
+    fs::create_dir_all(dir)?;
+    archive.extract_all(dir)?;
+    fs::write(dir.join(TOC_FILE), &archive.toc)?;
+    fs::write(dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+
+    Ok(manifest)
+}
+
+/// How the compile jobs of a plan are run.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// number of jobs running at the same time
+    pub jobs: usize,
+    /// a module compiling for longer is killed and reported as failed
+    pub timeout: Option<Duration>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            jobs: 1,
+            timeout: None,
+        }
+    }
+}
+
+fn run_job(job: &CompileJob, tools: &Tools, dir: &Path, timeout: Option<Duration>) -> Result<(), Error> {
+    let frontend = match job.frontend {
+        Some(frontend) => frontend,
+        None => return Ok(()),
+    };
+
+    let output = exec::run(frontend.command(tools).args(&job.args).current_dir(dir), timeout)?;
+    if !output.success() {
+        bail!(RecompileError::CompileFailed { file: job.name.clone(), status: output.status, log: output.stderr });
+    }
+    eprint!("{}", output.stderr);
+    println!("file {} compiled in {}.{:03}s", job.name, output.elapsed.as_secs(), output.elapsed.subsec_millis());
+
+    Ok(())
+}
+
+/// Extract the modules of the bundle into `dir` and run every compile job on up to `options.jobs` threads.
+///
+/// The objects are returned in the order of the plan whatever order the jobs finish in.
+/// All jobs run even when some fail, the failures are then reported together as `ModulesFailed`.
+pub fn compile(bundle: &Bundle, plan: &CompilePlan, dir: &Path, options: &CompileOptions) -> Result<Vec<String>, Error> {
+    bundle.archive()?.extract_all(dir)?;
+
+    let start = Instant::now();
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<(), Error>>>> = Mutex::new((0..plan.jobs.len()).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, plan.jobs.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let job = match plan.jobs.get(index) {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let result = run_job(job, &plan.toolchain.tools, dir, options.timeout);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    let failures = plan.jobs.iter()
+        .zip(results.into_inner().unwrap())
+        .filter_map(|(job, result)| match result {
+            Some(Err(e)) => Some(ModuleFailure::new(&job.name, &e)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if !failures.is_empty() {
+        bail!(RecompileError::ModulesFailed { count: failures.len(), total: plan.jobs.len(), failures: ModuleFailures(failures) });
+    }
+    let elapsed = start.elapsed();
+    println!("{} modules compiled in {}.{:03}s", plan.jobs.len(), elapsed.as_secs(), elapsed.subsec_millis());
+
+    Ok(plan.jobs.iter()
+        .map(|job| dir.join(&job.output).to_string_lossy().into_owned())
+        .collect())
+}
+
+/// The deployment target of a bundle: the value of its `-<os>_version_min` link option,
+/// else the version of the first `-triple` of the compile jobs.
+fn deployment_target(manifest: &BundleManifest, jobs: &[CompileJob]) -> Option<String> {
+    let option = manifest.link_options.iter()
+        .skip_while(|opt| !platform::is_version_min_option(opt))
+        .nth(1);
+    if let Some(version) = option {
+        return Some(version.clone());
+    }
+    jobs.iter()
+        .find_map(|job| job.args.iter().skip_while(|arg| arg.as_str() != "-triple").nth(1))
+        .and_then(|triple| platform::from_triple(triple))
+        .map(|(_, version)| version.to_string())
+        .filter(|version| !version.is_empty())
+}
+
+/// Link `objects` with the options recorded in the bundle, writing to `output` or the planned output.
+///
+/// The cross profile links with `ld64.lld`, which takes the deployment target from
+/// `-platform_version` only.
+pub fn link(plan: &CompilePlan, objects: &[String], output: Option<&str>) -> Result<(), Error> {
+    let manifest = &plan.manifest;
+    let toolchain = &plan.toolchain;
+    let cross = toolchain.profile == Profile::Cross;
+    let link_output = output.map(str::to_string).or_else(|| plan.output.clone()).unwrap_or_else(|| "a.out".to_string());
+
+    let recorded = if cross {
+        let mut recorded = Vec::new();
+        let mut iter = manifest.link_options.iter();
+        while let Some(opt) = iter.next() {
+            if platform::is_version_min_option(opt) {
+                iter.next();
+            } else {
+                recorded.push(opt.clone());
+            }
+        }
+        let target = platform::find(&manifest.platform).zip(deployment_target(manifest, &plan.jobs));
+        if let Some((platform, version)) = target.filter(|_| !recorded.iter().any(|opt| opt.as_str() == "-platform_version")) {
+            let sdk = if manifest.sdkversion.is_empty() { &version } else { &manifest.sdkversion };
+            recorded.extend(platform.platform_version(&version, sdk));
+        }
+        recorded
+    } else {
+        manifest.link_options.clone()
+    };
+    let mut link_options = Vec::new();
+    let mut iter = recorded.into_iter();
+    while let Some(opt) = iter.next() {
+        if opt.as_str() == "-executable_path" {
+            let path = iter.next();
+            // ld64.lld does not take a path after -executable_path
+            if !cross {
+                link_options.push(opt);
+                link_options.extend(path.map(|_| link_output.clone()));
+            }
+        } else {
+            link_options.push(opt);
+        }
+    }
+    link_options.push("-o".to_string());
+    link_options.push(link_output.clone());
+    link_options.push("-syslibroot".to_string());
+    link_options.push(toolchain.sdk_path.clone());
+    for dylib in manifest.frameworks() {
+        link_options.push(if dylib.weak { "-weak_framework" } else { "-framework" }.to_string());
+        link_options.push(dylib.framework().unwrap_or_default().to_string());
+    }
+
+    link_options.push("-lSystem".to_string());
+    if let Some(ref runtime) = toolchain.runtime {
+        link_options.push(runtime.to_string_lossy().into_owned());
+    }
+
+    for obj in objects {
+        link_options.push(obj.clone());
+    }
+
+    println!("link_options: {:?}", link_options);
+
+    let link_process = exec::run(Command::new(&toolchain.tools.ld).args(&link_options), None)?;
+    if !link_process.success() {
+        bail!(RecompileError::LinkFailed { output: link_output, status: link_process.status, log: link_process.stderr });
+    }
+    eprint!("{}", link_process.stderr);
+    println!("success!");
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+//! The Apple platforms a bundle can be built for, and what each is called by the
+//! SDK, the clang runtime, the target triple and the linker.
+
+/// One platform of the table.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Platform {
+    /// the `platform` of the bundle manifest, e.g. `iPhoneOS`
+    pub name: &'static str,
+    /// `<sdk_dir>.platform` and `<sdk_dir>.sdk` of Xcode, not `name` for Mac Catalyst
+    pub sdk_dir: &'static str,
+    /// the SDK name of `xcrun --sdk` and `SDKROOT`
+    pub sdk: &'static str,
+    /// `libclang_rt.<runtime>.a`
+    pub runtime: &'static str,
+    /// the OS and environment of the target triple, `ios` and `simulator` of `arm64-apple-ios14.0-simulator`
+    pub triple_os: &'static str,
+    pub triple_environment: Option<&'static str>,
+    /// the platform of `ld -platform_version`
+    pub ld_platform: &'static str,
+    /// the ld64 options giving the deployment target before `-platform_version`
+    pub version_min_options: &'static [&'static str],
+    /// `platform` of `LC_BUILD_VERSION`
+    pub build_platform: u32,
+}
+
+pub const PLATFORMS: &[Platform] = &[
+    Platform {
+        name: "MacOSX",
+        sdk_dir: "MacOSX",
+        sdk: "macosx",
+        runtime: "osx",
+        triple_os: "macosx",
+        triple_environment: None,
+        ld_platform: "macos",
+        version_min_options: &["-macosx_version_min", "-macos_version_min"],
+        build_platform: 1,
+    },
+    Platform {
+        name: "iPhoneOS",
+        sdk_dir: "iPhoneOS",
+        sdk: "iphoneos",
+        runtime: "ios",
+        triple_os: "ios",
+        triple_environment: None,
+        ld_platform: "ios",
+        version_min_options: &["-ios_version_min", "-iphoneos_version_min"],
+        build_platform: 2,
+    },
+    Platform {
+        name: "AppleTVOS",
+        sdk_dir: "AppleTVOS",
+        sdk: "appletvos",
+        runtime: "tvos",
+        triple_os: "tvos",
+        triple_environment: None,
+        ld_platform: "tvos",
+        version_min_options: &["-tvos_version_min"],
+        build_platform: 3,
+    },
+    Platform {
+        name: "WatchOS",
+        sdk_dir: "WatchOS",
+        sdk: "watchos",
+        runtime: "watchos",
+        triple_os: "watchos",
+        triple_environment: None,
+        ld_platform: "watchos",
+        version_min_options: &["-watchos_version_min"],
+        build_platform: 4,
+    },
+    Platform {
+        name: "MacCatalyst",
+        sdk_dir: "MacOSX",
+        sdk: "macosx",
+        runtime: "osx",
+        triple_os: "ios",
+        triple_environment: Some("macabi"),
+        ld_platform: "mac-catalyst",
+        version_min_options: &[],
+        build_platform: 6,
+    },
+    Platform {
+        name: "iPhoneSimulator",
+        sdk_dir: "iPhoneSimulator",
+        sdk: "iphonesimulator",
+        runtime: "iossim",
+        triple_os: "ios",
+        triple_environment: Some("simulator"),
+        ld_platform: "ios-simulator",
+        version_min_options: &["-ios_simulator_version_min"],
+        build_platform: 7,
+    },
+    Platform {
+        name: "AppleTVSimulator",
+        sdk_dir: "AppleTVSimulator",
+        sdk: "appletvsimulator",
+        runtime: "tvossim",
+        triple_os: "tvos",
+        triple_environment: Some("simulator"),
+        ld_platform: "tvos-simulator",
+        version_min_options: &["-tvos_simulator_version_min"],
+        build_platform: 8,
+    },
+    Platform {
+        name: "WatchSimulator",
+        sdk_dir: "WatchSimulator",
+        sdk: "watchsimulator",
+        runtime: "watchossim",
+        triple_os: "watchos",
+        triple_environment: Some("simulator"),
+        ld_platform: "watchos-simulator",
+        version_min_options: &["-watchos_simulator_version_min"],
+        build_platform: 9,
+    },
+    Platform {
+        name: "XROS",
+        sdk_dir: "XROS",
+        sdk: "xros",
+        runtime: "xros",
+        triple_os: "xros",
+        triple_environment: None,
+        ld_platform: "xros",
+        version_min_options: &[],
+        build_platform: 11,
+    },
+    Platform {
+        name: "XRSimulator",
+        sdk_dir: "XRSimulator",
+        sdk: "xrsimulator",
+        runtime: "xrossim",
+        triple_os: "xros",
+        triple_environment: Some("simulator"),
+        ld_platform: "xros-simulator",
+        version_min_options: &[],
+        build_platform: 12,
+    },
+];
+
+/// The platform a bundle manifest names.
+pub fn find(name: &str) -> Option<&'static Platform> {
+    PLATFORMS.iter().find(|platform| platform.name == name)
+}
+
+/// The platform of an `LC_BUILD_VERSION` load command.
+pub fn from_build_platform(build_platform: u32) -> Option<&'static Platform> {
+    PLATFORMS.iter().find(|platform| platform.build_platform == build_platform)
+}
+
+/// The platform of a target triple such as `arm64-apple-ios9.0.0`, and its deployment target.
+pub fn from_triple(triple: &str) -> Option<(&'static Platform, &str)> {
+    let mut parts = triple.split('-').skip(2);
+    let os = parts.next()?;
+    let environment = parts.next();
+    let split = os.find(|c: char| c.is_ascii_digit()).unwrap_or(os.len());
+    let (name, version) = os.split_at(split);
+    let name = if name == "macos" { "macosx" } else { name };
+    PLATFORMS.iter()
+        .find(|platform| platform.triple_os == name && platform.triple_environment == environment)
+        .map(|platform| (platform, version))
+}
+
+/// One of the `-<os>_version_min` options of any platform.
+pub fn is_version_min_option(option: &str) -> bool {
+    PLATFORMS.iter().any(|platform| platform.version_min_options.contains(&option))
+}
+
+impl Platform {
+    pub fn library(&self) -> String {
+        format!("libclang_rt.{}.a", self.runtime)
+    }
+
+    /// The triple for `arch`, `version` may be empty.
+    pub fn triple(&self, arch: &str, version: &str) -> String {
+        match self.triple_environment {
+            Some(environment) => format!("{}-apple-{}{}-{}", arch, self.triple_os, version, environment),
+            None => format!("{}-apple-{}{}", arch, self.triple_os, version),
+        }
+    }
+
+    /// `-platform_version <platform> <min_version> <sdk_version>`.
+    pub fn platform_version(&self, min_version: &str, sdk_version: &str) -> Vec<String> {
+        vec!["-platform_version".to_string(), self.ld_platform.to_string(), min_version.to_string(), sdk_version.to_string()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triples() {
+        let (platform, version) = from_triple("arm64-apple-ios9.0.0").unwrap();
+        assert_eq!((platform.name, version), ("iPhoneOS", "9.0.0"));
+        let (platform, version) = from_triple("x86_64-apple-macosx10.14.0").unwrap();
+        assert_eq!((platform.name, version), ("MacOSX", "10.14.0"));
+        let (platform, version) = from_triple("x86_64-apple-macos11").unwrap();
+        assert_eq!((platform.name, version), ("MacOSX", "11"));
+        let (platform, version) = from_triple("arm64-apple-ios14.0-simulator").unwrap();
+        assert_eq!((platform.name, version), ("iPhoneSimulator", "14.0"));
+        let (platform, version) = from_triple("x86_64-apple-ios13.1-macabi").unwrap();
+        assert_eq!((platform.name, version), ("MacCatalyst", "13.1"));
+        let (platform, version) = from_triple("arm64_32-apple-watchos5.0").unwrap();
+        assert_eq!((platform.name, version), ("WatchOS", "5.0"));
+        let (platform, version) = from_triple("arm64-apple-xros").unwrap();
+        assert_eq!((platform.name, version), ("XROS", ""));
+
+        assert!(from_triple("x86_64-unknown-linux-gnu").is_none());
+        assert!(from_triple("arm64-apple-ios14.0-unknown").is_none());
+        assert!(from_triple("arm64").is_none());
+    }
+
+    #[test]
+    fn triple_round_trip() {
+        for platform in PLATFORMS {
+            let triple = platform.triple("arm64", "1.0");
+            let (parsed, version) = from_triple(&triple).unwrap();
+            // Mac Catalyst and the iOS simulator share the os with iOS, the environment tells them apart
+            assert_eq!((parsed.name, version), (platform.name, "1.0"), "{}", triple);
+        }
+        assert_eq!(find("AppleTVSimulator").unwrap().triple("x86_64", "12.0"), "x86_64-apple-tvos12.0-simulator");
+        assert_eq!(find("iPhoneOS").unwrap().triple("armv7", ""), "armv7-apple-ios");
+    }
+
+    #[test]
+    fn lookups() {
+        assert_eq!(find("WatchOS").unwrap().library(), "libclang_rt.watchos.a");
+        assert!(find("watchos").is_none());
+        assert_eq!(from_build_platform(6).unwrap().name, "MacCatalyst");
+        assert!(from_build_platform(0).is_none());
+        assert!(is_version_min_option("-ios_simulator_version_min"));
+        assert!(!is_version_min_option("-platform_version"));
+        assert_eq!(find("XRSimulator").unwrap().platform_version("1.0", "1.1"),
+                   vec!["-platform_version", "xros-simulator", "1.0", "1.1"]);
+    }
+}
@@ -0,0 +1,329 @@
+//! Check the toolchain and SDK can rebuild a bundle before any module is compiled.
+
+extern crate failure;
+extern crate plist;
+extern crate serde_json;
+
+use self::failure::Error;
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use bitcode::ModuleHeader;
+use exec;
+use pipeline::CompilePlan;
+use toolchain::Profile;
+
+const SDK_SETTINGS_JSON: &str = "SDKSettings.json";
+const SDK_SETTINGS_PLIST: &str = "SDKSettings.plist";
+
+/// Xcode releases by the first component of their Apple clang version.
+const XCODE_RELEASES: &[(u32, &str)] = &[
+    (700, "7.0 - 7.2"),
+    (703, "7.3"),
+    (800, "8.0 - 8.2"),
+    (802, "8.3"),
+    (900, "9.0 - 9.2"),
+    (902, "9.3 - 9.4"),
+    (1000, "10.0 - 10.1"),
+    (1001, "10.2 - 10.3"),
+    (1100, "11.0 - 11.3"),
+    (1103, "11.4 - 11.7"),
+    (1200, "12.0 - 12.4"),
+    (1205, "12.5"),
+    (1300, "13.0 - 13.2"),
+    (1316, "13.3 - 13.4"),
+    (1400, "14.0 - 14.2"),
+    (1403, "14.3"),
+];
+
+fn parse_numbers(version: &str) -> Vec<u32> {
+    version.split('.')
+        .map(|part| part.chars().take_while(char::is_ascii_digit).collect::<String>())
+        .take_while(|part| !part.is_empty())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+/// A compiler version as a bitcode producer or `clang --version` gives it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompilerVersion {
+    /// `clang-902.0.39.1` of Xcode
+    Apple(Vec<u32>),
+    /// an open source LLVM release
+    Llvm(Vec<u32>),
+}
+
+impl CompilerVersion {
+    /// `APPLE_1_902.0.39.1_0` or `LLVM14.0.6`.
+    pub fn from_producer(producer: &str) -> Option<CompilerVersion> {
+        if producer.starts_with("APPLE_") {
+            let version = producer.split('_').nth(2)?;
+            return Some(CompilerVersion::Apple(parse_numbers(version)));
+        }
+        producer.strip_prefix("LLVM").map(|version| CompilerVersion::Llvm(parse_numbers(version)))
+    }
+
+    /// The Xcode release shipping this compiler.
+    pub fn xcode(&self) -> Option<&'static str> {
+        match *self {
+            CompilerVersion::Apple(ref version) => {
+                let major = *version.first()?;
+                XCODE_RELEASES.iter().find(|release| release.0 == major).map(|release| release.1)
+            },
+            CompilerVersion::Llvm(_) => None,
+        }
+    }
+
+    /// The releases are compatible with each other: Apple clang with the same first
+    /// component, LLVM with the same major version.
+    fn release(&self) -> Option<u32> {
+        match *self {
+            CompilerVersion::Apple(ref version) | CompilerVersion::Llvm(ref version) => version.first().cloned(),
+        }
+    }
+}
+
+impl fmt::Display for CompilerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |version: &[u32]| version.iter().map(u32::to_string).collect::<Vec<_>>().join(".");
+        match *self {
+            CompilerVersion::Apple(ref version) => {
+                write!(f, "clang-{}", join(version))?;
+                match self.xcode() {
+                    Some(xcode) => write!(f, " (Xcode {})", xcode),
+                    None => Ok(()),
+                }
+            },
+            CompilerVersion::Llvm(ref version) => write!(f, "LLVM {}", join(version)),
+        }
+    }
+}
+
+/// What `clang --version` reports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClangVersion {
+    pub compiler: CompilerVersion,
+    /// `14.0.3` of `Apple clang version 14.0.3 (clang-1403.0.22.14.1)`, which names
+    /// the resource directory of the compiler
+    pub release: String,
+}
+
+impl ClangVersion {
+    /// `Apple LLVM version 9.1.0 (clang-902.0.39.1)` or `clang version 14.0.6`.
+    pub fn parse(output: &str) -> Option<ClangVersion> {
+        let line = output.lines().next()?;
+        if !line.contains("clang") && !line.contains("LLVM") {
+            return None;
+        }
+        let start = line.find("version ")? + "version ".len();
+        let release = parse_numbers(line[start..].split_whitespace().next().unwrap_or(""));
+        if release.is_empty() {
+            return None;
+        }
+        let compiler = match line.find("(clang-") {
+            Some(start) => CompilerVersion::Apple(parse_numbers(line[start + "(clang-".len()..].trim_end_matches(')'))),
+            None => CompilerVersion::Llvm(release.clone()),
+        };
+        Some(ClangVersion {
+            compiler,
+            release: release.iter().map(u32::to_string).collect::<Vec<_>>().join("."),
+        })
+    }
+}
+
+/// The version `clang --version` reports.
+pub fn clang_version(clang: &Path) -> Result<Option<ClangVersion>, Error> {
+    let output = exec::run(Command::new(clang).arg("--version"), None)?;
+    if !output.success() {
+        bail!("{} --version failed ({})", clang.display(), output.status);
+    }
+    Ok(ClangVersion::parse(&output.stdout))
+}
+
+/// `Version` of `SDKSettings.json`, or of `SDKSettings.plist` for older SDKs.
+pub fn sdk_version(sdk: &Path) -> Result<Option<String>, Error> {
+    let json = sdk.join(SDK_SETTINGS_JSON);
+    if json.is_file() {
+        let settings: serde_json::Value = serde_json::from_slice(&fs::read(&json)?)?;
+        return Ok(settings.get("Version").and_then(|version| version.as_str()).map(str::to_string));
+    }
+    let plist = sdk.join(SDK_SETTINGS_PLIST);
+    if plist.is_file() {
+        let settings = plist::Value::from_file(&plist)?;
+        return Ok(settings.as_dictionary()
+            .and_then(|dict| dict.get("Version"))
+            .and_then(|version| version.as_string())
+            .map(str::to_string));
+    }
+    Ok(None)
+}
+
+/// `12.1.0` and `12.1` are the same version.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let trim = |version: &str| {
+        let mut numbers = parse_numbers(version);
+        while numbers.last() == Some(&0) {
+            numbers.pop();
+        }
+        numbers
+    };
+    trim(a).cmp(&trim(b))
+}
+
+/// Something about the toolchain or SDK that differs from what built the bundle.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub message: String,
+    /// the recompilation is refused unless forced
+    pub blocking: bool,
+}
+
+impl Mismatch {
+    fn new(message: String, blocking: bool) -> Mismatch {
+        Mismatch { message, blocking }
+    }
+}
+
+/// `cross` is the cross profile, which reads Apple bitcode with an upstream clang on purpose.
+fn check_compiler(producer: &CompilerVersion, toolchain: &CompilerVersion, cross: bool, mismatches: &mut Vec<Mismatch>) {
+    let required = match producer.xcode() {
+        Some(xcode) => format!(", use Xcode {}", xcode),
+        None => String::new(),
+    };
+    let same_kind = matches!((producer, toolchain),
+                             (CompilerVersion::Apple(_), CompilerVersion::Apple(_)) | (CompilerVersion::Llvm(_), CompilerVersion::Llvm(_)));
+    if !same_kind {
+        mismatches.push(Mismatch::new(format!("bitcode produced by {} cannot be read reliably by {}{}", producer, toolchain, required), !cross));
+        return;
+    }
+    match toolchain.release().cmp(&producer.release()) {
+        Ordering::Less => {
+            mismatches.push(Mismatch::new(format!("bitcode produced by {} is newer than {}{}", producer, toolchain, required), true));
+        },
+        Ordering::Greater => {
+            mismatches.push(Mismatch::new(format!("{} is newer than {}, which produced the bitcode{}", toolchain, producer, required), false));
+        },
+        Ordering::Equal => {},
+    }
+}
+
+/// Compare the producers of the modules and the SDK version of the bundle with the
+/// `clang` and the SDK the plan compiles and links with.
+pub fn check(plan: &CompilePlan, headers: &[(String, ModuleHeader)], clang: &Path) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+
+    let mut producers: Vec<CompilerVersion> = Vec::new();
+    for (_, header) in headers {
+        if let Some(version) = header.producer.as_ref().and_then(|producer| CompilerVersion::from_producer(producer)) {
+            if !producers.contains(&version) {
+                producers.push(version);
+            }
+        }
+    }
+    if !producers.is_empty() {
+        match clang_version(clang) {
+            Ok(Some(toolchain)) => {
+                for producer in &producers {
+                    check_compiler(producer, &toolchain.compiler, plan.toolchain.profile == Profile::Cross, &mut mismatches);
+                }
+            },
+            Ok(None) => mismatches.push(Mismatch::new(format!("cannot tell the version of {}", clang.display()), false)),
+            Err(e) => mismatches.push(Mismatch::new(format!("cannot tell the version of {}: {}", clang.display(), e), false)),
+        }
+    }
+
+    let sdk = Path::new(&plan.toolchain.sdk_path);
+    let bundle_sdk = &plan.manifest.sdkversion;
+    match sdk_version(sdk) {
+        Ok(None) => mismatches.push(Mismatch::new(format!("no SDK settings in {}, its version is not checked", sdk.display()), false)),
+        Ok(Some(ref version)) if !bundle_sdk.is_empty() => match compare_versions(version, bundle_sdk) {
+            Ordering::Less => {
+                mismatches.push(Mismatch::new(format!("the bundle was built with SDK {}, {} is SDK {}", bundle_sdk, sdk.display(), version), true));
+            },
+            Ordering::Greater => {
+                mismatches.push(Mismatch::new(format!("the bundle was built with SDK {}, {} is the newer SDK {}", bundle_sdk, sdk.display(), version), false));
+            },
+            Ordering::Equal => {},
+        },
+        Ok(_) => {},
+        Err(e) => mismatches.push(Mismatch::new(format!("cannot read the settings of {}: {}", sdk.display(), e), false)),
+    }
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn producers() {
+        assert_eq!(CompilerVersion::from_producer("APPLE_1_902.0.39.1_0"), Some(CompilerVersion::Apple(vec![902, 0, 39, 1])));
+        assert_eq!(CompilerVersion::from_producer("APPLE_1_1403.0.22.14.1_0"), Some(CompilerVersion::Apple(vec![1403, 0, 22, 14, 1])));
+        assert_eq!(CompilerVersion::from_producer("LLVM14.0.6"), Some(CompilerVersion::Llvm(vec![14, 0, 6])));
+        assert_eq!(CompilerVersion::from_producer("LLVM17.0.0git"), Some(CompilerVersion::Llvm(vec![17, 0, 0])));
+        assert_eq!(CompilerVersion::from_producer("APPLE_1"), None);
+        assert_eq!(CompilerVersion::from_producer("GCC"), None);
+    }
+
+    #[test]
+    fn clang_versions() {
+        let parse = |output: &str| ClangVersion::parse(output).map(|version| (version.compiler, version.release));
+        let apple = "Apple LLVM version 9.1.0 (clang-902.0.39.1)\nTarget: x86_64-apple-darwin17.5.0\n";
+        assert_eq!(parse(apple), Some((CompilerVersion::Apple(vec![902, 0, 39, 1]), "9.1.0".to_string())));
+        let xcode14 = "Apple clang version 14.0.3 (clang-1403.0.22.14.1)\n";
+        assert_eq!(parse(xcode14), Some((CompilerVersion::Apple(vec![1403, 0, 22, 14, 1]), "14.0.3".to_string())));
+        let upstream = "Ubuntu clang version 14.0.6-2\nTarget: x86_64-pc-linux-gnu\n";
+        assert_eq!(parse(upstream), Some((CompilerVersion::Llvm(vec![14, 0, 6]), "14.0.6".to_string())));
+        assert_eq!(parse("gcc (GCC) 12.2.0"), None);
+        assert_eq!(parse("clang version unknown"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn xcode_releases() {
+        let version = CompilerVersion::Apple(vec![902, 0, 39, 1]);
+        assert_eq!(version.xcode(), Some("9.3 - 9.4"));
+        assert_eq!(version.to_string(), "clang-902.0.39.1 (Xcode 9.3 - 9.4)");
+        assert_eq!(CompilerVersion::Apple(vec![700, 1, 81]).xcode(), Some("7.0 - 7.2"));
+        assert_eq!(CompilerVersion::Apple(vec![703, 0, 29]).xcode(), Some("7.3"));
+        assert_eq!(CompilerVersion::Apple(vec![1]).xcode(), None);
+        assert_eq!(CompilerVersion::Llvm(vec![14, 0, 6]).to_string(), "LLVM 14.0.6");
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("12.1.0", "12.1"), Ordering::Equal);
+        assert_eq!(compare_versions("12.0", "12.1"), Ordering::Less);
+        assert_eq!(compare_versions("12.10", "12.9"), Ordering::Greater);
+        assert_eq!(compare_versions("13", "12.4.1"), Ordering::Greater);
+        assert_eq!(compare_versions("", "0.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn compilers() {
+        let check = |producer: &CompilerVersion, toolchain: &CompilerVersion, cross: bool| {
+            let mut mismatches = Vec::new();
+            check_compiler(producer, toolchain, cross, &mut mismatches);
+            mismatches.iter().map(|mismatch| mismatch.blocking).collect::<Vec<_>>()
+        };
+        let xcode9 = CompilerVersion::Apple(vec![902, 0, 39, 1]);
+        let xcode9_later = CompilerVersion::Apple(vec![902, 0, 39, 2]);
+        let xcode10 = CompilerVersion::Apple(vec![1000, 11, 45, 2]);
+        let llvm = CompilerVersion::Llvm(vec![14, 0, 6]);
+
+        assert!(check(&xcode9, &xcode9_later, false).is_empty());
+        assert_eq!(check(&xcode10, &xcode9, false), vec![true]);
+        assert_eq!(check(&xcode9, &xcode10, false), vec![false]);
+        let mut mismatches = Vec::new();
+        check_compiler(&xcode9, &xcode10, false, &mut mismatches);
+        assert_eq!(mismatches[0].message,
+                   "clang-1000.11.45.2 (Xcode 10.0 - 10.1) is newer than clang-902.0.39.1 (Xcode 9.3 - 9.4), which produced the bitcode, use Xcode 9.3 - 9.4");
+        assert_eq!(check(&xcode9, &llvm, false), vec![true]);
+        assert_eq!(check(&xcode9, &llvm, true), vec![false]);
+    }
+}
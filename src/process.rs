@@ -1,345 +1,564 @@
 extern crate mach_object;
 extern crate failure;
-extern crate xml;
 
 use mach_object::*;
 use self::failure::Error;
-use self::xml::reader::{EventReader, XmlEvent};
 
-use std::process::{Command, Stdio, exit};
+use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::fs;
-use std::io::{Write, BufReader, stderr, stdout, SeekFrom, Seek, Read};
+use std::io::{Write, stderr, Cursor, SeekFrom, Seek};
+use std::time::Duration;
 
+use archive::{self, ArMember};
+use bitcode;
+use error::RecompileError;
+use exec;
 use context::*;
+use elf::{self, ElfFile};
+use fat;
+use pipeline::{self, find_section, CompileOptions, CompilePlan, ModuleHeaders, SEGMENT_NAME, SECTION_NAME};
+use preflight::{self, Mismatch};
+use toolchain::{self, ToolchainOptions};
+
+const BITCODE_SECTION: &str = "__bitcode";
+const CMDLINE_SECTION: &str = "__cmdline";
+const ELF_BITCODE_SECTION: &str = ".llvmbc";
+const ELF_CMDLINE_SECTION: &str = ".llvmcmd";
+
+/// Write `module` into `dir` next to its object and run `clang -cc1` with the embedded command line.
+fn compile_module(clang: &Path, dir: &Path, name: &str, module: &[u8], cmdline: &[String], timeout: Option<Duration>) -> Result<PathBuf, Error> {
+    let bitcode_file = format!("{}.bc", name);
+    let obj_file = format!("{}.o", name);
+    fs::write(dir.join(&bitcode_file), module)?;
+
+    let mut command = Command::new(clang);
+    command.arg("-cc1")
+        .args(cmdline)
+        .args(["-x", "ir", bitcode_file.as_str(), "-o", obj_file.as_str()])
+        .current_dir(dir);
+    let output = exec::run(&mut command, timeout)?;
+    if !output.success() {
+        bail!(RecompileError::CompileFailed { file: name.to_string(), status: output.status, log: output.stderr });
+    }
+    eprint!("{}", output.stderr);
+    println!("file {} compiled...", name);
+
+    Ok(dir.join(obj_file))
+}
+
+/// The clang objects are recompiled with, `--clang` or the one of the ToolChain.
+fn object_clang(toolchain: &ToolchainOptions) -> Result<PathBuf, Error> {
+    match toolchain.clang {
+        Some(ref clang) => Ok(PathBuf::from(clang)),
+        None => Ok(toolchain::resolve_tools(toolchain)?.1.clang),
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "a.out".to_string())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
 
-const SEGMENT_NAME: &'static str = "__LLVM";
-const SECTION_NAME: &'static str = "__bundle";
+/// `./<name of input>`, or `./<stem>.recompiled.<extension>` when that is the input
+/// itself, which is mapped while it is recompiled.
+fn output_name(input: &str) -> String {
+    let name = file_name(input);
+    let output = format!("./{}", name);
+    if !same_file(Path::new(&output), Path::new(input)) {
+        return output;
+    }
+    let path = Path::new(&name);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("./{}.recompiled.{}", stem.to_string_lossy(), extension.to_string_lossy()),
+        _ => format!("./{}.recompiled", name),
+    }
+}
+
+/// `__LLVM,__cmdline` holds the cc1 arguments separated by NUL.
+fn decode_cmdline(data: &[u8]) -> Vec<String> {
+    data.split(|c| *c == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
 
 pub struct MachOProcess {
-    re_info: ReCompilerInfo,
-    xml_file: String,
-    xar_file: String,
+    input: String,
+    arch_name: Option<String>,
+    output: Option<String>,
+    /// `(arch, path)` of the slices of the last fat file
+    slices: Vec<(String, String)>,
+    universal: bool,
+    force: bool,
+    work_dir: PathBuf,
+    options: CompileOptions,
+}
+
+impl Default for MachOProcess {
+    fn default() -> MachOProcess {
+        MachOProcess::new()
+    }
 }
 
 impl MachOProcess {
     pub fn new() -> MachOProcess {
         MachOProcess {
-            re_info: ReCompilerInfo::new(),
-            xml_file: String::from("tmp.xml"),
-            xar_file: String::from("tmp.xar"),
+            input: String::new(),
+            arch_name: None,
+            output: None,
+            slices: Vec::new(),
+            universal: false,
+            force: false,
+            work_dir: PathBuf::from("."),
+            options: CompileOptions::default(),
         }
     }
 
-    pub fn handle_ofile(&mut self, ofile: &OFile, ctxt: &mut FileContext, sdk_path: Option<String>, tool_chain: Option<String>) -> Result<bool, Error> {
+    pub fn set_input(&mut self, input: &str) {
+        self.input = input.to_string();
+    }
 
-        match ofile {
-            &OFile::MachFile {
-                ref header,
-                ref commands,
-            } => {
-                if self.handle_macho_file(header, commands, ctxt).unwrap() == true {
-                    if self.parse_xml(sdk_path, tool_chain).unwrap() == false {
-                        writeln!(stderr(), "parse xml failed.")?;
-                        return Ok(false);
-                    }
-                    if self.compiler_files().unwrap() == false {
-                        writeln!(stderr(), "compile file failed.")?;
-                        return Ok(false);
-                    }
-                    if self.link_objects().unwrap() == false {
-                        writeln!(stderr(), "link error.")?;
-                        return Ok(false);
+    pub fn set_universal(&mut self, universal: bool) {
+        self.universal = universal;
+    }
+
+    /// Directory for the intermediate files, see `WorkDir`.
+    pub fn set_work_dir(&mut self, work_dir: &Path) {
+        self.work_dir = work_dir.to_path_buf();
+    }
+
+    /// Compile even when the toolchain or SDK does not match the bundle.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    /// Number of modules of a bundle compiled at the same time.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.options.jobs = jobs;
+    }
+
+    /// Kill a compiler running longer than `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.options.timeout = timeout;
+    }
+
+    /// The file written by the last `handle_ofile`, `None` for the slices of a fat file
+    /// that are not merged, see `slices`.
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    /// `(arch, path)` of every slice written for the last fat file, merged or not.
+    pub fn slices(&self) -> &[(String, String)] {
+        &self.slices
+    }
+
+    fn input_name(&self) -> String {
+        file_name(&self.input)
+    }
+
+    /// Where the slice `arch` of `output` is written: next to `output`, or into the work
+    /// directory when only the merged universal file is kept.
+    fn slice_path(&self, output: &str, arch: &str) -> String {
+        let path = fat::slice_output(output, arch);
+        if self.universal {
+            self.work_dir.join(file_name(&path)).to_string_lossy().into_owned()
+        } else {
+            path
+        }
+    }
+
+    /// Refuse a toolchain or SDK that does not match what built the bundle, unless forced.
+    fn preflight(&self, plan: &CompilePlan, bundle: &pipeline::Bundle) -> Result<(), Error> {
+        // a module header the parser does not understand must not stop the recompilation
+        let headers = pipeline::module_headers(bundle, &plan.manifest).unwrap_or_else(|e| ModuleHeaders {
+            headers: Vec::new(),
+            unreadable: vec![e.to_string()],
+        });
+        let mut mismatches = preflight::check(plan, &headers.headers, &plan.toolchain.tools.clang);
+        mismatches.extend(headers.unreadable.into_iter().map(|message| Mismatch {
+            message: format!("cannot read the module header, its producer is not checked: {}", message),
+            blocking: false,
+        }));
+
+        let mut blocking = Vec::new();
+        for mismatch in mismatches {
+            if mismatch.blocking && !self.force {
+                blocking.push(mismatch.message);
+            } else {
+                writeln!(stderr(), "warning: {}", mismatch.message)?;
+            }
+        }
+        if !blocking.is_empty() {
+            bail!(RecompileError::ToolChainMismatch(blocking.join("\n")));
+        }
+        Ok(())
+    }
+
+
+    /// Write the bundle modules of a linked Mach-O file into `dir`, one sub directory per slice of a fat file.
+    pub fn extract_ofile(&self, ofile: &OFile, ctxt: &mut FileContext, dir: &Path) -> Result<(), Error> {
+        match *ofile {
+            OFile::MachFile { ref commands, .. } => {
+                let payload = *ctxt.cur.get_ref();
+                let bundle = match pipeline::extract_bundle(commands, payload)? {
+                    Some(bundle) => bundle,
+                    None => bail!(RecompileError::MissingBundle(self.input.clone())),
+                };
+                let manifest = pipeline::extract_to(&bundle, dir)?;
+                println!("{} modules extracted to {}", manifest.files.len(), dir.display());
+                Ok(())
+            },
+            OFile::FatFile { ref files, .. } => {
+                let payload = *ctxt.cur.get_ref();
+                for (arch, file) in files {
+                    let arch_name = fat::arch_name(arch);
+                    let start = arch.offset as usize;
+                    let end = start + arch.size as usize;
+                    if end > payload.len() {
+                        bail!(RecompileError::malformed("fat file", format!("slice {} exceeds the file", arch_name)));
                     }
+                    let mut slice_ctxt = FileContext::new(&payload[start..end]);
+                    self.extract_ofile(file, &mut slice_ctxt, &dir.join(&arch_name))?;
                 }
-                return Ok(true);
-            },
-            &OFile::FatFile { magic, ref files } => {
-                writeln!(stderr(), "No support");
-                Ok(false)
-            },
-            &OFile::ArFile { ref files } => {
-                writeln!(stderr(), "No support");
-                Ok(false)
-            },
-            &OFile::SymDef { ref ranlibs } => {
-                writeln!(stderr(), "No support");
-                Ok(false)
+                Ok(())
             },
+            _ => bail!(RecompileError::MissingBundle(self.input.clone())),
         }
     }
 
-    fn parse_element<T>(&mut self, parse: &mut EventReader<T>, local_name: &String) -> Result<bool, Error> where T: Read {
+    pub fn handle_ofile(&mut self, ofile: &OFile, ctxt: &mut FileContext, toolchain: &ToolchainOptions) -> Result<(), Error> {
+        self.slices.clear();
 
-        match local_name.as_str() {
-            "platform" => {
-                loop {
-                    match parse.next().unwrap() {
-                        XmlEvent::Characters( data ) => {
-                            self.re_info.set_platform(data.clone());
-                        },
-                        XmlEvent::EndElement { name } => {
-                            break;
-                        },
-                        _ => {}
-                    }
+        match *ofile {
+            OFile::MachFile {
+                ref header,
+                ref commands,
+            } => {
+                if header.filetype == MH_OBJECT {
+                    return self.handle_object_file(ctxt, &object_clang(toolchain)?);
+                }
+                let payload = *ctxt.cur.get_ref();
+                let bundle = match pipeline::extract_bundle(commands, payload)? {
+                    Some(bundle) => bundle,
+                    None => bail!(RecompileError::MissingBundle(self.input.clone())),
                 };
-            },
-            "option" => {
-                loop {
-                    match parse.next().unwrap() {
-                        XmlEvent::Characters( data ) => {
-                            //println!("character option: {}", data);
-                            self.re_info.push_option(data);
-                        },
-                        XmlEvent::EndElement { name } => {
-                            //println!("xml node end: {}", name);
-                            break;
-                        },
-                        _ => {}
-                    };
+                println!("Spayloads of ({}, {}) section", SEGMENT_NAME, SECTION_NAME);
+
+                let manifest = pipeline::parse_manifest(&bundle)?;
+                let plan = pipeline::plan_compilation(manifest, toolchain)?;
+                println!("sdk: {} (from {})", plan.toolchain.sdk_path, plan.toolchain.sdk_source);
+                println!("ToolChain: {} (from {})", plan.toolchain.tool_chain, plan.toolchain.tool_chain_source);
+                println!("clang: {}, ld: {}", plan.toolchain.tools.clang.display(), plan.toolchain.tools.ld.display());
+                if let Some(ref runtime) = plan.toolchain.runtime {
+                    println!("runtime: {}", runtime.display());
+                }
+                self.preflight(&plan, &bundle)?;
+                println!("link framework: {:?}", plan.manifest.frameworks());
+                println!("link option: {:?}", plan.manifest.link_options);
+                for file in &plan.manifest.files {
+                    println!("file {} ({}): {:?}", file.name, file.file_type.as_str(), if file.is_swift() { &file.swift } else { &file.clang });
+                }
+
+                let objects = pipeline::compile(&bundle, &plan, &self.work_dir, &self.options)?;
+                let output = match (plan.output.as_ref(), self.arch_name.as_ref()) {
+                    (Some(output), Some(arch)) => Some(self.slice_path(output, arch)),
+                    _ => None,
                 };
+                pipeline::link(&plan, &objects, output.as_deref())?;
+                self.output = plan.output;
+                Ok(())
             },
-            "lib" => {
-                loop {
-                    match parse.next().unwrap() {
-                        XmlEvent::Characters( data ) => {
-                            //println!("character framework: {}", data);
-                            self.re_info.push_framework(&mut data.clone());
+            OFile::FatFile { ref files, .. } => {
+                let payload = *ctxt.cur.get_ref();
+                let mut slices = Vec::new();
+                let mut output = None;
+
+                for (arch, file) in files {
+                    let arch_name = fat::arch_name(arch);
+                    let start = arch.offset as usize;
+                    let end = start + arch.size as usize;
+                    if end > payload.len() {
+                        bail!(RecompileError::malformed("fat file", format!("slice {} exceeds the file", arch_name)));
+                    }
+                    println!("process slice {}", arch_name);
+
+                    self.output = None;
+                    self.arch_name = Some(arch_name.clone());
+
+                    // the modules of every slice share names, keep them apart
+                    let work_dir = self.work_dir.clone();
+                    self.work_dir = work_dir.join(&arch_name);
+                    fs::create_dir_all(&self.work_dir)?;
+
+                    let mut slice_ctxt = FileContext::new(&payload[start..end]);
+                    let result = self.handle_ofile(file, &mut slice_ctxt, toolchain);
+                    let slice = self.output.as_ref().map(|output| self.slice_path(output, &arch_name));
+                    self.work_dir = work_dir;
+                    result?;
+                    match slice {
+                        Some(slice) => {
+                            output = self.output.take();
+                            slices.push((arch.clone(), slice));
                         },
-                        XmlEvent::EndElement { name } => {
-                            //println!("xml node end: {}", name);
-                            break;
+                        None if self.universal => {
+                            bail!(RecompileError::UnsupportedFile(format!("slice {} of {} has no output to merge", arch_name, self.input)));
                         },
-                        _ => {}
-                    };
-                };
+                        None => {},
+                    }
+                }
+                self.arch_name = None;
+                self.slices = slices.iter().map(|(arch, path)| (fat::arch_name(arch), path.clone())).collect();
+
+                if let (true, Some(output)) = (self.universal, output) {
+                    fat::write_universal(&slices, Path::new(&output))?;
+                    println!("universal binary {} created.", output);
+                    self.output = Some(output);
+                }
+                Ok(())
             },
-            "name" => {
-                loop {
-                    match parse.next().unwrap() {
-                        XmlEvent::Characters( data ) => {
-                            //println!("character file name: {}", data);
-                            self.re_info.add_new_file_vec(data);
-                        },
-                        XmlEvent::EndElement { name } => {
-                            //println!("xml node end: {}", name);
-                            break;
-                        },
-                        _ => {}
-                    };
+            OFile::ArFile { .. } => {
+                let payload = *ctxt.cur.get_ref();
+                let members = archive::read_members(payload)?;
+
+                let clang = object_clang(toolchain)?;
+                let mut objects = Vec::with_capacity(members.len());
+                for (index, member) in members.iter().enumerate() {
+                    if member.is_symdef() {
+                        objects.push(None);
+                        continue;
+                    }
+                    let name = format!("{}_{}", index, member.name);
+                    objects.push(self.compile_object(&name, member.data, &clang)?);
+                }
+                if objects.iter().all(Option::is_none) {
+                    bail!(RecompileError::MissingBitcode(self.input.clone()));
+                }
+
+                let rebuilt = members.iter()
+                    .zip(objects.iter())
+                    .map(|(member, object)| match *object {
+                        Some(ref data) => ArMember { data, ..member.clone() },
+                        None => member.clone(),
+                    })
+                    .collect::<Vec<_>>();
+
+                let output = output_name(&self.input);
+                let path = match self.arch_name {
+                    Some(ref arch) => self.slice_path(&output, arch),
+                    None => output.clone(),
                 };
+                archive::write_archive(&rebuilt, Path::new(&path))?;
+                println!("archive {} created.", path);
+                self.output = Some(output);
+                Ok(())
             },
-            "cmd" => {
-                loop {
-                    match parse.next().unwrap() {
-                        XmlEvent::Characters( data ) => {
-                            //println!("character file cmd: {}", data);
-                            self.re_info.add_file_cmd(data);
-                        },
-                        XmlEvent::EndElement { name } => {
-                            //println!("xml node end: {}", name);
-                            break;
-                        },
-                        _ => {}
-                    };
+            OFile::SymDef { .. } => {
+                bail!(RecompileError::UnsupportedFile(self.input.clone()))
+            },
+        }
+    }
+
+    /// A relocatable object compiled with -fembed-bitcode, recompiled in place of the bundle flow.
+    fn handle_object_file(&mut self, ctxt: &mut FileContext, clang: &Path) -> Result<(), Error> {
+        let payload = *ctxt.cur.get_ref();
+        let name = self.input_name();
+
+        match self.compile_object(&name, payload, clang)? {
+            Some(object) => {
+                let output = output_name(&self.input);
+                let path = match self.arch_name {
+                    Some(ref arch) => self.slice_path(&output, arch),
+                    None => output.clone(),
                 };
-            }
-            _ => { }
+                fs::write(&path, object)?;
+                println!("object {} created.", path);
+                self.output = Some(output);
+                Ok(())
+            },
+            None => {
+                bail!(RecompileError::MissingBitcode(self.input.clone()))
+            },
         }
-        return Ok(true);
     }
 
-    fn parse_xml(&mut self, sdk_path: Option<String>, tool_chain: Option<String>) -> Result<bool, Error> {
+    /// Recompile an object carrying `__LLVM,__bitcode`, returns `None` when it has no bitcode.
+    fn compile_object(&mut self, name: &str, payload: &[u8], clang: &Path) -> Result<Option<Vec<u8>>, Error> {
+        let ofile = OFile::parse(&mut Cursor::new(payload))?;
+        let commands = match ofile {
+            OFile::MachFile { ref commands, .. } => commands,
+            _ => return Ok(None),
+        };
+
+        let mut ctxt = FileContext::new(payload);
+        let module = match find_section(commands, SEGMENT_NAME, BITCODE_SECTION) {
+            Some(sect) => {
+                ctxt.cur.seek(SeekFrom::Start(u64::from(sect.offset)))?;
+                ctxt.section_hex(sect.addr, sect.size)?
+            },
+            None => return Ok(None),
+        };
+        // -fembed-bitcode-marker only leaves a placeholder byte
+        if module.len() <= 1 {
+            writeln!(stderr(), "{} only carries a bitcode marker.", name)?;
+            return Ok(None);
+        }
+        let cmdline = match find_section(commands, SEGMENT_NAME, CMDLINE_SECTION) {
+            Some(sect) => {
+                ctxt.cur.seek(SeekFrom::Start(u64::from(sect.offset)))?;
+                decode_cmdline(&ctxt.section_hex(sect.addr, sect.size)?)
+            },
+            None => Vec::new(),
+        };
 
-        let xml_file = fs::File::open(self.xml_file.as_str()).expect("tmp xml file open failed.");
-        let xml_file = BufReader::new(xml_file);
+        let obj_file = compile_module(clang, &self.work_dir, name, &module, &cmdline, self.options.timeout)?;
+        Ok(Some(fs::read(&obj_file)?))
+    }
+}
 
-        let mut parse = EventReader::new(xml_file);
+/// Recompiles the `.llvmbc` modules of an ELF object, executable or shared object.
+pub struct ElfProcess {
+    input: String,
+    clang: PathBuf,
+    work_dir: PathBuf,
+    timeout: Option<Duration>,
+}
 
-        loop {
-            let node = parse.next().unwrap();
-            match node {
-                XmlEvent::EndDocument => {
-                    break;
-                },
-                XmlEvent::StartDocument { .. } => {
-                    println!("start to parse xml");
-                }
-                XmlEvent::StartElement { name, .. } => {
-                    //println!("xml node start: {}", name);
-                    if self.parse_element::<BufReader<fs::File>>(&mut parse, &name.local_name).unwrap() == false {
-                        return Ok(false);
-                    }
-                },
-                XmlEvent::EndElement { name } => {
-                    //println!("xml node end: {}", name);
-                },
-                _ => {},
-            }
-        }
+impl Default for ElfProcess {
+    fn default() -> ElfProcess {
+        ElfProcess::new()
+    }
+}
 
-        if self.re_info.choose_path_from_platform(sdk_path, tool_chain).unwrap() == false {
-            return Ok(false);
+impl ElfProcess {
+    pub fn new() -> ElfProcess {
+        ElfProcess {
+            input: String::new(),
+            clang: PathBuf::from("clang"),
+            work_dir: PathBuf::from("."),
+            timeout: None,
         }
+    }
 
-        for index in 0..self.re_info.file_compile.len() {
-            let mut out = self.re_info.file_compile[index][0].clone();
-            out.push_str(".o");
-            self.re_info.obj_file.push(out.clone());
-            let mut list = vec!["-x".to_string(), "ir".to_string(), "-o".to_string(), out];
-            self.re_info.file_compile[index].append(&mut list);
-        }
-        println!("link framework: {:?}", self.re_info.link_framework);
-        println!("link option: {:?}", self.re_info.link_options);
-        println!("file cmd: {:?}", self.re_info.file_compile);
-        return Ok(true);
+    pub fn set_input(&mut self, input: &str) {
+        self.input = input.to_string();
     }
 
-    fn compiler_files(&mut self) -> Result<bool, Error> {
-
-        let mut process = Command::new("xar")
-            .arg("-xf")
-            .arg(self.xar_file.as_str())
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()
-            .expect("xar error.");
-
-        let _result = process.wait().unwrap();
-        if _result.code().unwrap() == 0 {
-            for v in &self.re_info.file_compile {
-                let mut process_compile = Command::new("clang").arg("-cc1").args(v).spawn().unwrap();
-                if process.wait().unwrap().code().unwrap() != 0 {
-                    writeln!(stderr(), "file compiled failed.")?;
-                    return Ok(false);
-                } else {
-                    println!("file {} compiled...", v[0]);
-                }
-            }
-        } else {
-            writeln!(stderr(), "xar extracts failed.")?;
-            return Ok(false);
-        }
-        return Ok(true);
+    /// The clang compiling and linking the modules, `clang` of `PATH` by default.
+    pub fn set_clang(&mut self, clang: &Path) {
+        self.clang = clang.to_path_buf();
     }
 
-    fn handle_macho_file(&mut self, header: &MachHeader, commands: &[MachCommand], ctxt: &mut FileContext) -> Result<bool, Error> {
-        let commands = commands.iter()
-            .map(|load| load.command())
-            .cloned()
-            .collect::<Vec<LoadCommand>>();
-
-        'outer: for cmd in &commands {
-            match *cmd {
-                LoadCommand::Segment {ref sections, ..} | LoadCommand::Segment64 {ref sections, ..} => {
-                    for ref sect in sections {
-                        let name = Some((sect.segname.clone(), Some(sect.sectname.clone())));
-
-                        if name == Some((String::from(SEGMENT_NAME), Some(String::from(SECTION_NAME)))) {
-                            writeln!(stdout(), "Spayloads of ({}, {}) section", sect.segname, sect.sectname)?;
-
-                            ctxt.cur.seek(SeekFrom::Start(sect.offset as u64))?;
-
-                            let dump = ctxt.section_hex(sect.addr, sect.size)?;
-
-                            let mut output_file = fs::File::create(self.xar_file.as_str()).unwrap();
-                            output_file.write(&dump[..])?;
-
-                            let mut process = Command::new("xar")
-                                .arg("--dump-toc=".to_owned() + self.xml_file.as_str())
-                                .arg("-f")
-                                .arg(self.xar_file.as_str())
-                                .stdout(Stdio::piped())
-                                .stdin(Stdio::piped())
-                                .spawn()
-                                .expect("xar failed.");
-                            let _result = process.wait().unwrap();
-                            if _result.code().unwrap() == 0 {
-                                writeln!(stdout(), "xar dump xml finish.")?;
-                                return Ok(true);
-                            } else {
-                                writeln!(stderr(), "xar dump xml failed.")?;
-                                return Ok(false);
-                            }
-                            break 'outer;
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-        return Ok(false);
+    /// Directory for the intermediate files, see `WorkDir`.
+    pub fn set_work_dir(&mut self, work_dir: &Path) {
+        self.work_dir = work_dir.to_path_buf();
     }
 
-    fn link_objects(&mut self) -> Result<bool, Error> {
+    /// Kill a compiler running longer than `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
 
-        let mut link_options = Vec::new();
+    pub fn handle_elf(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let elf = ElfFile::parse(payload)?;
 
-        let mut out_path = false;
-        for opt in &mut self.re_info.link_options {
-            if out_path {
-                let mut output = String::new();
-                output.push_str("./");
-                let off = match opt.rfind("/") {
-                    Some(index) => {
-                        index + 1
-                    }
-                    None => 0
-                };
-                output.push_str(opt.split_off(off).as_str());
-                link_options.push(output);
-                out_path = false;
-            } else {
-                link_options.push(opt.clone());
-            }
-            if opt.as_str() == "-executable_path" {
-                out_path = true;
-            }
+        let modules = match elf.section(ELF_BITCODE_SECTION) {
+            Some(data) => bitcode::split_modules(data),
+            None => bail!(RecompileError::MissingBitcode(self.input.clone())),
+        };
+        if modules.is_empty() {
+            bail!(RecompileError::MissingBitcode(self.input.clone()));
         }
-        link_options.push("-syslibroot".to_string());
-        link_options.push(self.re_info.sdk_path.clone());
-        for framework in &self.re_info.link_framework {
-            link_options.push("-framework".to_string());
-            link_options.push(framework.clone());
+        let cmdlines = elf.section(ELF_CMDLINE_SECTION)
+            .map(elf::split_cmdlines)
+            .unwrap_or_default();
+        if cmdlines.len() != modules.len() {
+            writeln!(stderr(), "{} modules but {} command lines, reuse the first command line.", modules.len(), cmdlines.len())?;
         }
 
-        let mut search = Command::new("find")
-            .arg(self.re_info.tool_chain.clone())
-            .arg("-name")
-            .arg(self.re_info.lib_clang.clone())
-            .output()
-            .expect("find libclang_rt error.");
-        if search.status.success() {
-            if let Ok(mut lib_clang)= String::from_utf8(search.stdout) {
-                //lib_clang.retain(|c| c != '\n');
-                lib_clang.pop();
-                link_options.push("-lSystem".to_string());
-                link_options.push(lib_clang);
+        let name = file_name(&self.input);
+        let mut obj_files = Vec::with_capacity(modules.len());
+        for (index, module) in modules.iter().enumerate() {
+            let cmdline = if cmdlines.len() == modules.len() {
+                &cmdlines[index]
             } else {
-                writeln!(stderr(), "find libclang_rt error.");
-                return Ok(false);
-            }
-        } else {
-            //println!("{}, {}, {}",self.re_info.sdk_path, self.re_info.tool_chain, self.re_info.lib_clang);
-            writeln!(stderr(), "find libclang_rt failed.");
-            return Ok(false);
+                match cmdlines.first() {
+                    Some(cmdline) => cmdline,
+                    None => &[][..],
+                }
+            };
+            obj_files.push(compile_module(&self.clang, &self.work_dir, &format!("{}.{}", name, index), module, cmdline, self.timeout)?.to_string_lossy().into_owned());
         }
 
-        for obj in &self.re_info.obj_file {
-            link_options.push(obj.clone());
+        let output = output_name(&self.input);
+        if elf.e_type == elf::ET_REL && obj_files.len() == 1 {
+            fs::copy(&obj_files[0], &output)?;
+            println!("object {} created.", output);
+            return Ok(());
         }
 
+        let mut link_options = Vec::new();
+        if let Some(triple) = cmdlines.first().and_then(|cmdline| cmdline.iter().skip_while(|arg| *arg != "-triple").nth(1)) {
+            link_options.push(format!("--target={}", triple));
+        }
+        match elf.e_type {
+            elf::ET_REL => link_options.push("-r".to_string()),
+            // PIE executables are ET_DYN as well
+            elf::ET_DYN if elf.is_pie()? => link_options.push("-pie".to_string()),
+            elf::ET_DYN => {
+                link_options.push("-shared".to_string());
+                if let Some(soname) = elf.soname()? {
+                    link_options.push(format!("-Wl,-soname,{}", soname));
+                }
+            },
+            _ => {},
+        }
+        for path in elf.runpath()? {
+            link_options.push(format!("-Wl,-rpath,{}", path));
+        }
+        link_options.append(&mut obj_files);
+        for lib in elf.needed()? {
+            // the driver adds the C runtime and the dynamic loader itself
+            if lib.starts_with("libc.so") || lib.starts_with("ld-") {
+                continue;
+            }
+            link_options.push(format!("-l:{}", lib));
+        }
+        link_options.push("-o".to_string());
+        link_options.push(output.clone());
+
         println!("link_options: {:?}", link_options);
 
-        let mut link_process = Command::new("ld").args(link_options).spawn().unwrap();
-        let _result = link_process.wait().unwrap();
-        if _result.code().unwrap() == 0 {
-            println!("success!");
-        } else {
-            writeln!(stderr(), "ld error!");
-            return Ok(false);
+        let link_process = exec::run(Command::new(&self.clang).args(&link_options), None)?;
+        if !link_process.success() {
+            bail!(RecompileError::LinkFailed { output, status: link_process.status, log: link_process.stderr });
         }
+        eprint!("{}", link_process.stderr);
+        println!("success!");
+
+        Ok(())
+    }
+}
 
-        return Ok(true);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_next_to_the_input() {
+        // tests run in the package directory
+        assert_eq!(output_name("Cargo.toml"), "./Cargo.recompiled.toml");
+        assert_eq!(output_name("./LICENSE"), "./LICENSE.recompiled");
+        assert_eq!(output_name("src/lib.rs"), "./lib.rs");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,79 @@
+//! Find the `libclang_rt` runtime library a binary is linked with.
+//!
+//! A ToolChain may hold the resource directories of several clang releases, the runtime
+//! is taken from the one of the clang that compiles the modules.
+
+extern crate failure;
+
+use self::failure::Error;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use error::RecompileError;
+use preflight;
+
+/// `lib/darwin/<library>` of `resource`, where the resource directory holds it.
+fn darwin_library(resource: &Path, library: &str) -> Option<PathBuf> {
+    Some(resource.join("lib").join("darwin").join(library)).filter(|path| path.is_file())
+}
+
+/// Every `(version, path)` of `library` in `tool_chain`: the resource directories under
+/// `usr/lib/clang` of Xcode, `lib/clang` of an LLVM install, or `tool_chain` itself
+/// when it is a resource directory.
+fn candidates(tool_chain: &Path, library: &str) -> Vec<(String, PathBuf)> {
+    let mut found = Vec::new();
+    if let Some(path) = darwin_library(tool_chain, library) {
+        let version = tool_chain.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        found.push((version, path));
+    }
+    for clang in &[tool_chain.join("usr").join("lib").join("clang"), tool_chain.join("lib").join("clang")] {
+        let entries = match fs::read_dir(clang) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if let Some(path) = darwin_library(&entry.path(), library) {
+                found.push((entry.file_name().to_string_lossy().into_owned(), path));
+            }
+        }
+    }
+    found.sort_by_key(|candidate| version_numbers(&candidate.0));
+    found
+}
+
+fn version_numbers(version: &str) -> Vec<u32> {
+    version.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+fn major(version: &str) -> &str {
+    version.split('.').next().unwrap_or(version)
+}
+
+/// `library`, such as `libclang_rt.ios.a`, of the resource directory in `tool_chain`
+/// matching the version of `clang`: the same version, else the same major version.
+/// A single runtime is taken whatever its version.
+pub fn find_runtime(tool_chain: &Path, clang: &Path, library: &str) -> Result<PathBuf, Error> {
+    let mut candidates = candidates(tool_chain, library);
+    if candidates.is_empty() {
+        bail!(RecompileError::RuntimeNotFound(format!("no {} in {} or its usr/lib/clang/*/lib/darwin", library, tool_chain.display())));
+    }
+    if candidates.len() == 1 {
+        return Ok(candidates.remove(0).1);
+    }
+
+    let versions = candidates.iter().map(|candidate| candidate.0.as_str()).collect::<Vec<_>>().join(", ");
+    let version = match preflight::clang_version(clang) {
+        Ok(Some(version)) => version.release,
+        _ => bail!(RecompileError::RuntimeNotFound(format!("{} of clang {} and cannot tell the version of {}", library, versions, clang.display()))),
+    };
+    if let Some(index) = candidates.iter().position(|candidate| candidate.0 == version) {
+        return Ok(candidates.remove(index).1);
+    }
+    // the newest of the same major version
+    if let Some(index) = candidates.iter().rposition(|candidate| major(&candidate.0) == major(&version)) {
+        return Ok(candidates.remove(index).1);
+    }
+
+    bail!(RecompileError::RuntimeNotFound(format!("no {} of clang {} in {}, only of clang {}", library, version, tool_chain.display(), versions)))
+}
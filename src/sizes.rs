@@ -0,0 +1,301 @@
+//! Per-function size differences between an original and a recompiled binary.
+
+extern crate failure;
+extern crate mach_object;
+
+use self::failure::Error;
+use mach_object::{OFile, SectionAttributes, Symbol, SymbolReader};
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+
+use compare::{self, ThinFile};
+use elf;
+use error::RecompileError;
+
+/// A function found in the symbol table, sized up to the next symbol of its section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSize {
+    pub name: String,
+    /// `segment,section` the function is in
+    pub section: String,
+    pub size: u64,
+}
+
+fn is_code(section: &mach_object::Section) -> bool {
+    section.flags.sect_attrs().intersects(SectionAttributes::S_ATTR_PURE_INSTRUCTIONS | SectionAttributes::S_ATTR_SOME_INSTRUCTIONS)
+}
+
+/// Size every function of a thin file from the gaps between the symbols of its code
+/// sections, the last symbol of a section ends with the section.
+pub fn function_sizes(file: &ThinFile) -> Vec<FunctionSize> {
+    // (section, address, name) of every defined symbol in code
+    let mut symbols = Vec::new();
+    let mut cur = Cursor::new(file.payload);
+    if let Some(iter) = file.ofile.symbols(&mut cur) {
+        for symbol in iter {
+            if let Symbol::Defined { name: Some(name), external, section: Some(section), entry, .. } = symbol {
+                // assembler temporary labels, not functions
+                if !external && (name.starts_with('l') || name.starts_with('L')) {
+                    continue;
+                }
+                if is_code(&section) {
+                    symbols.push((section, entry as u64, name.to_string()));
+                }
+            }
+        }
+    }
+    symbols.sort_by_key(|symbol| (symbol.0.addr, symbol.1));
+
+    let mut sizes = Vec::new();
+    for (index, &(ref section, addr, ref name)) in symbols.iter().enumerate() {
+        let section_end = (section.addr + section.size) as u64;
+        // aliases share an address, the function ends at the next different one
+        let end = symbols[index + 1..].iter()
+            .find(|next| next.0.addr == section.addr && next.1 > addr)
+            .map(|next| next.1)
+            .unwrap_or(section_end);
+        sizes.push(FunctionSize {
+            name: name.clone(),
+            section: format!("{},{}", section.segname, section.sectname),
+            size: end.saturating_sub(addr),
+        });
+    }
+
+    sizes
+}
+
+/// How a function fared in the recompilation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Unchanged,
+    Grown,
+    Shrunk,
+    Added,
+    Removed,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            Change::Unchanged => "unchanged",
+            Change::Grown => "grown",
+            Change::Shrunk => "shrunk",
+            Change::Added => "added",
+            Change::Removed => "removed",
+        })
+    }
+}
+
+/// One function of the original or the recompiled binary, paired by name.
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeDelta {
+    pub name: String,
+    pub section: String,
+    /// `None` when the function is not in that binary
+    pub original: Option<u64>,
+    pub recompiled: Option<u64>,
+    pub delta: i64,
+    pub change: Change,
+}
+
+impl SizeDelta {
+    fn new(name: &str, original: Option<&FunctionSize>, recompiled: Option<&FunctionSize>) -> SizeDelta {
+        let original_size = original.map(|function| function.size);
+        let recompiled_size = recompiled.map(|function| function.size);
+        let delta = recompiled_size.unwrap_or(0) as i64 - original_size.unwrap_or(0) as i64;
+        let change = match (original_size, recompiled_size) {
+            (None, _) => Change::Added,
+            (_, None) => Change::Removed,
+            _ if delta > 0 => Change::Grown,
+            _ if delta < 0 => Change::Shrunk,
+            _ => Change::Unchanged,
+        };
+
+        SizeDelta {
+            name: name.to_string(),
+            section: recompiled.or(original).map(|function| function.section.clone()).unwrap_or_default(),
+            original: original_size,
+            recompiled: recompiled_size,
+            delta,
+            change,
+        }
+    }
+}
+
+/// What the rows of a report are ordered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Section,
+    Original,
+    Recompiled,
+    /// largest change first, growing or shrinking
+    Delta,
+}
+
+impl FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SortKey, Error> {
+        Ok(match s {
+            "name" => SortKey::Name,
+            "section" => SortKey::Section,
+            "original" => SortKey::Original,
+            "recompiled" => SortKey::Recompiled,
+            "delta" => SortKey::Delta,
+            _ => bail!("unknown sort key \"{}\", expected name, section, original, recompiled or delta", s),
+        })
+    }
+}
+
+/// The functions of one architecture.
+#[derive(Debug, Clone, Serialize)]
+pub struct SliceSizes {
+    pub arch: String,
+    pub original_total: u64,
+    pub recompiled_total: u64,
+    pub functions: Vec<SizeDelta>,
+}
+
+impl SliceSizes {
+    /// Pair the functions of `original` and `recompiled` by name.
+    pub fn new(arch: &str, original: &[FunctionSize], recompiled: &[FunctionSize]) -> SliceSizes {
+        // a local name may be in several objects, only the first one is paired
+        let mut names: BTreeMap<&str, (Option<&FunctionSize>, Option<&FunctionSize>)> = BTreeMap::new();
+        for function in original {
+            names.entry(&function.name).or_insert((None, None)).0.get_or_insert(function);
+        }
+        for function in recompiled {
+            names.entry(&function.name).or_insert((None, None)).1.get_or_insert(function);
+        }
+
+        SliceSizes {
+            arch: arch.to_string(),
+            original_total: original.iter().map(|function| function.size).sum(),
+            recompiled_total: recompiled.iter().map(|function| function.size).sum(),
+            functions: names.into_iter()
+                .map(|(name, (original, recompiled))| SizeDelta::new(name, original, recompiled))
+                .collect(),
+        }
+    }
+
+    pub fn sort(&mut self, key: SortKey) {
+        self.functions.sort_by(|a, b| {
+            let order = match key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Section => a.section.cmp(&b.section),
+                SortKey::Original => b.original.cmp(&a.original),
+                SortKey::Recompiled => b.recompiled.cmp(&a.recompiled),
+                SortKey::Delta => b.delta.abs().cmp(&a.delta.abs()),
+            };
+            order.then_with(|| a.name.cmp(&b.name))
+        });
+    }
+
+    /// Drop the functions whose size did not change.
+    pub fn retain_changed(&mut self) {
+        self.functions.retain(|function| function.change != Change::Unchanged);
+    }
+}
+
+/// The size differences of every architecture the two binaries share.
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeReport {
+    pub original: String,
+    pub recompiled: String,
+    pub slices: Vec<SliceSizes>,
+}
+
+fn thin_sizes(file_name: &str, payload: &[u8]) -> Result<Vec<(String, Vec<FunctionSize>)>, Error> {
+    if elf::is_elf(payload) {
+        bail!(RecompileError::UnsupportedFile(file_name.to_string()));
+    }
+    let ofile = OFile::parse(&mut Cursor::new(payload))?;
+    let sizes = compare::thin_files(&ofile, payload)?
+        .iter()
+        .map(|file| (file.arch.clone(), function_sizes(file)))
+        .collect::<Vec<_>>();
+    if sizes.is_empty() {
+        bail!(RecompileError::UnsupportedFile(file_name.to_string()));
+    }
+    Ok(sizes)
+}
+
+/// Pair the functions of the slices `original` and `recompiled` have in common.
+pub fn compare_sizes(original_name: &str, original: &[u8], recompiled_name: &str, recompiled: &[u8]) -> Result<SizeReport, Error> {
+    let original_sizes = thin_sizes(original_name, original)?;
+    let recompiled_sizes = thin_sizes(recompiled_name, recompiled)?;
+
+    let slices = original_sizes.iter()
+        .filter_map(|(arch, functions)| {
+            recompiled_sizes.iter()
+                .find(|other| other.0 == *arch)
+                .map(|other| SliceSizes::new(arch, functions, &other.1))
+        })
+        .collect::<Vec<_>>();
+    if slices.is_empty() {
+        bail!("{} and {} have no architecture in common", original_name, recompiled_name);
+    }
+
+    Ok(SizeReport {
+        original: original_name.to_string(),
+        recompiled: recompiled_name.to_string(),
+        slices,
+    })
+}
+
+fn size_cell(size: Option<u64>) -> String {
+    size.map(|size| size.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl SizeReport {
+    /// One header line and one line per function, the architecture in the first column.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("arch,name,section,original,recompiled,delta,change\n");
+        for slice in &self.slices {
+            for function in &slice.functions {
+                csv.push_str(&format!("{},{},{},{},{},{},{}\n",
+                                      slice.arch,
+                                      csv_field(&function.name),
+                                      csv_field(&function.section),
+                                      function.original.map(|size| size.to_string()).unwrap_or_default(),
+                                      function.recompiled.map(|size| size.to_string()).unwrap_or_default(),
+                                      function.delta,
+                                      function.change));
+            }
+        }
+        csv
+    }
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} against {}:", self.recompiled, self.original)?;
+        for slice in &self.slices {
+            writeln!(f, "  {}: {} bytes of code, was {} ({:+})", slice.arch, slice.recompiled_total, slice.original_total,
+                     slice.recompiled_total as i64 - slice.original_total as i64)?;
+            if slice.functions.is_empty() {
+                continue;
+            }
+            writeln!(f, "    {:>10} {:>10} {:>8}  {:<9}  {:<16}  name", "original", "recompiled", "delta", "change", "section")?;
+            for function in &slice.functions {
+                writeln!(f, "    {:>10} {:>10} {:>+8}  {:<9}  {:<16}  {}", size_cell(function.original), size_cell(function.recompiled),
+                         function.delta, function.change, function.section, function.name)?;
+            }
+        }
+        Ok(())
+    }
+}
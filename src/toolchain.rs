@@ -0,0 +1,380 @@
+//! Find the SDK and the ToolChain a bundle is rebuilt with.
+//!
+//! The SDK is the first of:
+//!
+//! 1. `--sdk`
+//! 2. `SDKROOT`, a path or an SDK name such as `iphoneos`
+//! 3. `$DEVELOPER_DIR/Platforms/<platform>.platform/Developer/SDKs/<platform>.sdk`, named
+//!    after the SDK of the platform table, `MacOSX` for Mac Catalyst
+//! 4. `xcrun --sdk <name> --show-sdk-path`
+//! 5. the same path in `/Applications/Xcode.app`
+//!
+//! The ToolChain is the first of:
+//!
+//! 1. `--tool`
+//! 2. `TOOLCHAINS`, an identifier or name looked up in the `Toolchains` directories
+//! 3. `$DEVELOPER_DIR/Toolchains/XcodeDefault.xctoolchain`
+//! 4. the toolchain of `xcrun --find clang`
+//! 5. `XcodeDefault.xctoolchain` in `/Applications/Xcode.app`
+//!
+//! An SDK of another platform than the bundle's is skipped, except when given by `--sdk`.
+//!
+//! clang, ld and the Swift frontend are those in `usr/bin` of the ToolChain, unless given
+//! by `--clang`, `--ld` or `--swift`.
+//!
+//! The cross profile rebuilds on a host without Xcode, such as Linux: the SDK is a copy
+//! of an Xcode SDK or its `.tbd` stubs found the same way, the modules are compiled by an
+//! upstream clang and linked by `ld64.lld`. Its ToolChain is `--tool` or the resource
+//! directory of that clang, where the runtime libraries are.
+
+extern crate failure;
+extern crate plist;
+
+use self::failure::Error;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use error::RecompileError;
+use exec;
+use platform::Platform;
+
+const DEFAULT_DEVELOPER_DIR: &str = "/Applications/Xcode.app/Contents/Developer";
+const DEFAULT_TOOLCHAIN_NAME: &str = "XcodeDefault.xctoolchain";
+
+/// Where a resolved SDK or ToolChain came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// `--sdk` or `--tool`
+    CommandLine,
+    SdkRoot,
+    Toolchains,
+    DeveloperDir,
+    Xcrun,
+    /// the standard Xcode location
+    Default,
+    /// `clang -print-resource-dir` of the cross profile
+    ResourceDir,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::SdkRoot => write!(f, "SDKROOT"),
+            Source::Toolchains => write!(f, "TOOLCHAINS"),
+            Source::DeveloperDir => write!(f, "DEVELOPER_DIR"),
+            Source::Xcrun => write!(f, "xcrun"),
+            Source::Default => write!(f, "default Xcode location"),
+            Source::ResourceDir => write!(f, "clang -print-resource-dir"),
+        }
+    }
+}
+
+/// A path and which step of the search order found it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    pub path: PathBuf,
+    pub source: Source,
+}
+
+impl Resolved {
+    fn new<P: Into<PathBuf>>(path: P, source: Source) -> Resolved {
+        Resolved { path: path.into(), source }
+    }
+}
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (from {})", self.path.display(), self.source)
+    }
+}
+
+/// Which tools a bundle is rebuilt with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// clang and ld of Xcode, on a Mac
+    #[default]
+    Xcode,
+    /// an upstream clang and `ld64.lld` with an SDK copied from a Mac, on any host
+    Cross,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Profile::Xcode => write!(f, "xcode"),
+            Profile::Cross => write!(f, "cross"),
+        }
+    }
+}
+
+/// What the command line says about the SDK and the tools, the rest is searched for.
+#[derive(Debug, Clone, Default)]
+pub struct ToolchainOptions {
+    pub profile: Profile,
+    /// `--sdk`
+    pub sdk: Option<String>,
+    /// `--tool`
+    pub tool_chain: Option<String>,
+    /// `--clang`, `--swift` and `--ld`, each used instead of the one of the ToolChain
+    pub clang: Option<String>,
+    pub swift: Option<String>,
+    pub ld: Option<String>,
+}
+
+/// The programs the modules are compiled and linked with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tools {
+    pub clang: PathBuf,
+    pub swift: PathBuf,
+    pub ld: PathBuf,
+}
+
+/// `usr/bin/<name>` of a ToolChain, unless `given` on the command line.
+fn toolchain_tool(bin: &Path, name: &str, given: Option<&str>) -> Result<PathBuf, Error> {
+    if let Some(path) = given {
+        return Ok(PathBuf::from(path));
+    }
+    let path = bin.join(name);
+    if !path.is_file() {
+        bail!(RecompileError::ToolChainNotFound(format!("no {} in {}, set --{}", name, bin.display(), name)));
+    }
+    Ok(path)
+}
+
+impl Tools {
+    /// The tools of `PATH`, `ld64.lld` as the linker of the cross profile, `options` overriding any of them.
+    pub fn new(options: &ToolchainOptions) -> Tools {
+        let ld = match options.profile {
+            Profile::Xcode => "ld",
+            Profile::Cross => "ld64.lld",
+        };
+        Tools {
+            clang: PathBuf::from(options.clang.as_deref().unwrap_or("clang")),
+            swift: PathBuf::from(options.swift.as_deref().unwrap_or("swift")),
+            ld: PathBuf::from(options.ld.as_deref().unwrap_or(ld)),
+        }
+    }
+
+    /// The tools in `usr/bin` of the ToolChain at `dir`, `options` overriding any of them.
+    pub fn from_toolchain(dir: &Path, options: &ToolchainOptions) -> Result<Tools, Error> {
+        let bin = dir.join("usr").join("bin");
+        let clang = toolchain_tool(&bin, "clang", options.clang.as_deref())?;
+        // `swift-frontend` since Swift 5.3, the `swift` driver runs the frontend before;
+        // a missing one only fails the Swift modules
+        let swift = match options.swift {
+            Some(ref swift) => PathBuf::from(swift),
+            None if bin.join("swift-frontend").is_file() => bin.join("swift-frontend"),
+            None => bin.join("swift"),
+        };
+        // the ToolChains of swift.org link with the ld of Xcode
+        let ld = match toolchain_tool(&bin, "ld", options.ld.as_deref()) {
+            Ok(ld) => ld,
+            Err(e) => match xcrun(&["--find", "ld"]) {
+                Some(ld) => PathBuf::from(ld),
+                None => return Err(e),
+            },
+        };
+        Ok(Tools { clang, swift, ld })
+    }
+}
+
+fn env_path(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// `DEVELOPER_DIR`, which may also name the Xcode application itself.
+fn developer_dir() -> Option<PathBuf> {
+    let dir = PathBuf::from(env_path("DEVELOPER_DIR")?);
+    let contents = dir.join("Contents").join("Developer");
+    Some(if contents.is_dir() { contents } else { dir })
+}
+
+/// First line of the output of `xcrun args`, `None` where there is no xcrun.
+fn xcrun(args: &[&str]) -> Option<String> {
+    let output = exec::run(Command::new("xcrun").args(args), None).ok()?;
+    if !output.success() {
+        return None;
+    }
+    output.stdout.lines().next().map(str::to_string).filter(|line| !line.is_empty())
+}
+
+/// `<platform>.sdk` of a developer directory, or a versioned `<platform>12.1.sdk`.
+fn platform_sdk(developer: &Path, platform: &Platform) -> Option<PathBuf> {
+    let sdks = developer.join("Platforms").join(format!("{}.platform", platform.sdk_dir)).join("Developer").join("SDKs");
+    let sdk = sdks.join(format!("{}.sdk", platform.sdk_dir));
+    if sdk.is_dir() {
+        return Some(sdk);
+    }
+    let mut versioned = fs::read_dir(&sdks).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().map(|name| name.to_string_lossy().starts_with(platform.sdk_dir)).unwrap_or(false))
+        .collect::<Vec<_>>();
+    versioned.sort();
+    versioned.pop()
+}
+
+fn matches_platform(sdk: &Path, platform: &Platform) -> bool {
+    sdk.file_name()
+        .map(|name| name.to_string_lossy().to_lowercase().starts_with(platform.sdk))
+        .unwrap_or(false)
+}
+
+/// The SDK of `platform`, see the module documentation for the search order.
+pub fn resolve_sdk(platform: &Platform, explicit: Option<&str>) -> Result<Resolved, Error> {
+    if let Some(sdk) = explicit {
+        if !Path::new(sdk).exists() {
+            bail!(RecompileError::SdkNotFound(sdk.to_string()));
+        }
+        if !sdk.contains(platform.sdk_dir) {
+            bail!(RecompileError::SdkMismatch { sdk: sdk.to_string(), platform: platform.name.to_string() });
+        }
+        return Ok(Resolved::new(sdk, Source::CommandLine));
+    }
+
+    if let Some(sdkroot) = env_path("SDKROOT") {
+        let path = if Path::new(&sdkroot).is_absolute() {
+            Some(PathBuf::from(&sdkroot))
+        } else {
+            xcrun(&["--sdk", &sdkroot, "--show-sdk-path"]).map(PathBuf::from)
+        };
+        if let Some(path) = path.filter(|path| path.is_dir() && matches_platform(path, platform)) {
+            return Ok(Resolved::new(path, Source::SdkRoot));
+        }
+    }
+    if let Some(sdk) = developer_dir().and_then(|dir| platform_sdk(&dir, platform)) {
+        return Ok(Resolved::new(sdk, Source::DeveloperDir));
+    }
+    if let Some(sdk) = xcrun(&["--sdk", platform.sdk, "--show-sdk-path"]).map(PathBuf::from) {
+        if sdk.is_dir() {
+            return Ok(Resolved::new(sdk, Source::Xcrun));
+        }
+    }
+    if let Some(sdk) = platform_sdk(Path::new(DEFAULT_DEVELOPER_DIR), platform) {
+        return Ok(Resolved::new(sdk, Source::Default));
+    }
+
+    bail!(RecompileError::SdkNotFound(format!("no {} SDK, set --sdk, SDKROOT or DEVELOPER_DIR", platform.sdk_dir)))
+}
+
+/// Directories holding `.xctoolchain` bundles, the ones of Xcode first.
+fn toolchain_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = developer_dir() {
+        dirs.push(dir.join("Toolchains"));
+    }
+    dirs.push(Path::new(DEFAULT_DEVELOPER_DIR).join("Toolchains"));
+    dirs.push(PathBuf::from("/Library/Developer/Toolchains"));
+    if let Some(home) = env_path("HOME") {
+        dirs.push(Path::new(&home).join("Library").join("Developer").join("Toolchains"));
+    }
+    dirs
+}
+
+/// Whether the `.xctoolchain` at `path` is the one `TOOLCHAINS` names, by its
+/// `CFBundleIdentifier`, one of its `Aliases` or its file name.
+fn is_toolchain(path: &Path, name: &str) -> bool {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    if stem.eq_ignore_ascii_case(name) {
+        return true;
+    }
+    let info = match plist::Value::from_file(path.join("Info.plist")) {
+        Ok(info) => info,
+        Err(_) => return false,
+    };
+    let dict = match info.as_dictionary() {
+        Some(dict) => dict,
+        None => return false,
+    };
+    let identifier = dict.get("CFBundleIdentifier").and_then(|value| value.as_string());
+    let aliases = dict.get("Aliases").and_then(|value| value.as_array());
+    identifier == Some(name) || aliases.map(|aliases| aliases.iter().any(|alias| alias.as_string() == Some(name))).unwrap_or(false)
+}
+
+fn find_toolchain(name: &str) -> Option<PathBuf> {
+    for dir in toolchain_dirs() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        let mut paths = entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect::<Vec<_>>();
+        paths.sort();
+        if let Some(path) = paths.into_iter().find(|path| is_toolchain(path, name)) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// The ToolChain, see the module documentation for the search order.
+pub fn resolve_toolchain(explicit: Option<&str>) -> Result<Resolved, Error> {
+    if let Some(tool_chain) = explicit {
+        if !Path::new(tool_chain).exists() {
+            bail!(RecompileError::ToolChainNotFound(tool_chain.to_string()));
+        }
+        return Ok(Resolved::new(tool_chain, Source::CommandLine));
+    }
+
+    if let Some(name) = env_path("TOOLCHAINS") {
+        // several names may be given, the first one found wins
+        for name in name.split_whitespace() {
+            if let Some(path) = find_toolchain(name) {
+                return Ok(Resolved::new(path, Source::Toolchains));
+            }
+        }
+    }
+    if let Some(path) = developer_dir().map(|dir| dir.join("Toolchains").join(DEFAULT_TOOLCHAIN_NAME)) {
+        if path.is_dir() {
+            return Ok(Resolved::new(path, Source::DeveloperDir));
+        }
+    }
+    // <toolchain>/usr/bin/clang
+    if let Some(clang) = xcrun(&["--find", "clang"]).map(PathBuf::from) {
+        if let Some(path) = clang.ancestors().nth(3) {
+            return Ok(Resolved::new(path, Source::Xcrun));
+        }
+    }
+    let path = Path::new(DEFAULT_DEVELOPER_DIR).join("Toolchains").join(DEFAULT_TOOLCHAIN_NAME);
+    if path.is_dir() {
+        return Ok(Resolved::new(path, Source::Default));
+    }
+
+    bail!(RecompileError::ToolChainNotFound("no ToolChain, set --tool, TOOLCHAINS or DEVELOPER_DIR".to_string()))
+}
+
+/// The ToolChain of the cross profile: `--tool`, or the resource directory of `clang`.
+pub fn resolve_cross_toolchain(clang: &Path, explicit: Option<&str>) -> Result<Resolved, Error> {
+    if let Some(tool_chain) = explicit {
+        if !Path::new(tool_chain).exists() {
+            bail!(RecompileError::ToolChainNotFound(tool_chain.to_string()));
+        }
+        return Ok(Resolved::new(tool_chain, Source::CommandLine));
+    }
+
+    let output = exec::run(Command::new(clang).arg("-print-resource-dir"), None)?;
+    let dir = output.stdout.lines().next().map(PathBuf::from).filter(|dir| dir.is_dir());
+    match dir {
+        Some(dir) if output.success() => Ok(Resolved::new(dir, Source::ResourceDir)),
+        _ => bail!(RecompileError::ToolChainNotFound(format!("no resource directory from {} -print-resource-dir, set --tool", clang.display()))),
+    }
+}
+
+/// The ToolChain and the tools of `options.profile`.
+pub fn resolve_tools(options: &ToolchainOptions) -> Result<(Resolved, Tools), Error> {
+    match options.profile {
+        Profile::Xcode => {
+            let tool_chain = resolve_toolchain(options.tool_chain.as_deref())?;
+            let tools = Tools::from_toolchain(&tool_chain.path, options)?;
+            Ok((tool_chain, tools))
+        },
+        Profile::Cross => {
+            let tools = Tools::new(options);
+            let tool_chain = resolve_cross_toolchain(&tools.clang, options.tool_chain.as_deref())?;
+            Ok((tool_chain, tools))
+        },
+    }
+}
@@ -0,0 +1,82 @@
+extern crate failure;
+
+use self::failure::Error;
+
+use std::env;
+use std::fs;
+use std::io::{self, Write, stderr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// The scratch directory of one run: extracted modules, bitcode and objects go here.
+///
+/// A directory created by `WorkDir` is removed when it is dropped unless it is kept,
+/// a directory that already existed is never removed.
+pub struct WorkDir {
+    path: PathBuf,
+    created: bool,
+    keep: bool,
+}
+
+impl WorkDir {
+    /// Use `dir` when given, otherwise create a unique directory under the system temp dir.
+    pub fn new(dir: Option<&Path>, keep: bool) -> Result<WorkDir, Error> {
+        let (path, created) = match dir {
+            Some(dir) => {
+                let created = !dir.exists();
+                fs::create_dir_all(dir)?;
+                (dir.to_path_buf(), created)
+            },
+            None => (Self::create_unique()?, true),
+        };
+
+        Ok(WorkDir {
+            path,
+            created,
+            keep,
+        })
+    }
+
+    fn create_unique() -> Result<PathBuf, Error> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos())
+            .unwrap_or(0);
+        loop {
+            let name = format!("BitcodeRecompiler-{}-{}-{}", process::id(), nanos, SEQUENCE.fetch_add(1, Ordering::SeqCst));
+            let path = env::temp_dir().join(name);
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(path),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.path.join(name)
+    }
+
+    pub fn set_keep(&mut self, keep: bool) {
+        self.keep = keep;
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if self.keep || !self.created {
+            return;
+        }
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            let _ = writeln!(stderr(), "cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}
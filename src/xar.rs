@@ -0,0 +1,478 @@
+extern crate byteorder;
+extern crate bzip2;
+extern crate failure;
+extern crate flate2;
+extern crate xml;
+
+use self::byteorder::{BigEndian, ReadBytesExt};
+use self::bzip2::read::BzDecoder;
+use self::failure::Error;
+use self::flate2::read::ZlibDecoder;
+use self::xml::reader::{EventReader, XmlEvent};
+
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+
+use error::RecompileError;
+
+const XAR_MAGIC: u32 = 0x7861_7221;
+const XAR_HEADER_SIZE: u16 = 28;
+
+const ENCODING_GZIP: &str = "application/x-gzip";
+const ENCODING_BZIP2: &str = "application/x-bzip2";
+const ENCODING_NONE: &str = "application/octet-stream";
+
+/// The fixed header at the beginning of every xar archive, stored big-endian.
+#[derive(Debug, Clone)]
+pub struct XarHeader {
+    pub magic: u32,
+    pub size: u16,
+    pub version: u16,
+    pub toc_length_compressed: u64,
+    pub toc_length_uncompressed: u64,
+    pub cksum_alg: u32,
+}
+
+impl XarHeader {
+    fn parse(payload: &[u8]) -> Result<XarHeader, Error> {
+        if payload.len() < XAR_HEADER_SIZE as usize {
+            bail!(RecompileError::MalformedXar("truncated xar header".to_string()));
+        }
+        let mut cur = Cursor::new(payload);
+
+        let header = XarHeader {
+            magic: cur.read_u32::<BigEndian>()?,
+            size: cur.read_u16::<BigEndian>()?,
+            version: cur.read_u16::<BigEndian>()?,
+            toc_length_compressed: cur.read_u64::<BigEndian>()?,
+            toc_length_uncompressed: cur.read_u64::<BigEndian>()?,
+            cksum_alg: cur.read_u32::<BigEndian>()?,
+        };
+
+        if header.magic != XAR_MAGIC {
+            bail!(RecompileError::MalformedXar(format!("bad xar magic 0x{:08x}", header.magic)));
+        }
+        if header.size < XAR_HEADER_SIZE {
+            bail!(RecompileError::MalformedXar(format!("bad xar header size {}", header.size)));
+        }
+
+        Ok(header)
+    }
+}
+
+/// How a heap entry is stored, taken from the `<encoding style="..."/>` element.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XarEncoding {
+    None,
+    Gzip,
+    Bzip2,
+}
+
+impl XarEncoding {
+    fn from_style(style: &str) -> Result<XarEncoding, Error> {
+        match style {
+            ENCODING_GZIP => Ok(XarEncoding::Gzip),
+            ENCODING_BZIP2 => Ok(XarEncoding::Bzip2),
+            ENCODING_NONE => Ok(XarEncoding::None),
+            _ => bail!(RecompileError::MalformedXar(format!("unsupported xar encoding {}", style))),
+        }
+    }
+}
+
+/// Location of a file's contents in the heap.
+#[derive(Debug, Clone)]
+pub struct XarData {
+    /// offset from the start of the heap
+    pub offset: u64,
+    /// number of bytes stored in the heap
+    pub length: u64,
+    /// number of bytes once decoded
+    pub size: u64,
+    pub encoding: XarEncoding,
+}
+
+/// A `<file>` entry of the TOC, directories are flattened into `/` separated names.
+#[derive(Debug, Clone)]
+pub struct XarFile {
+    pub id: String,
+    pub name: String,
+    pub data: Option<XarData>,
+}
+
+/// A xar archive read straight from memory, such as the `__LLVM,__bundle` section.
+pub struct XarArchive<'a> {
+    pub header: XarHeader,
+    pub toc: String,
+    pub files: Vec<XarFile>,
+    heap: &'a [u8],
+}
+
+impl<'a> XarArchive<'a> {
+    pub fn parse(payload: &'a [u8]) -> Result<XarArchive<'a>, Error> {
+        let header = XarHeader::parse(payload)?;
+
+        let toc_start = header.size as usize;
+        let toc_end = toc_start
+            .checked_add(header.toc_length_compressed as usize)
+            .filter(|end| *end <= payload.len())
+            .ok_or_else(|| RecompileError::MalformedXar("xar toc exceeds the archive".to_string()))?;
+
+        // the sizes come from the archive, never reserve more than it can hold
+        let mut toc = String::new();
+        ZlibDecoder::new(&payload[toc_start..toc_end])
+            .read_to_string(&mut toc)
+            .map_err(|e| RecompileError::MalformedXar(format!("bad xar toc, {}", e)))?;
+        if toc.len() as u64 != header.toc_length_uncompressed {
+            bail!(RecompileError::MalformedXar(format!("xar toc size mismatch, expect {} got {}", header.toc_length_uncompressed, toc.len())));
+        }
+
+        let files = Self::parse_toc(&toc)?;
+
+        Ok(XarArchive {
+            header,
+            toc,
+            files,
+            heap: &payload[toc_end..],
+        })
+    }
+
+    fn parse_toc(toc: &str) -> Result<Vec<XarFile>, Error> {
+        let mut files: Vec<XarFile> = Vec::new();
+        // indices into `files` of the enclosing `<file>` elements
+        let mut parents: Vec<usize> = Vec::new();
+        // path of element names below the innermost `<file>`
+        let mut elements: Vec<String> = Vec::new();
+        let mut data: Option<XarData> = None;
+        let mut encoding = XarEncoding::None;
+
+        let mut parse = EventReader::new(toc.as_bytes());
+        loop {
+            let event = parse.next()
+                .map_err(|e| RecompileError::MalformedXar(format!("bad xar toc, {}", e)))?;
+            match event {
+                XmlEvent::EndDocument => break,
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    match name.local_name.as_str() {
+                        "file" => {
+                            let id = attributes.iter()
+                                .find(|attr| attr.name.local_name == "id")
+                                .map(|attr| attr.value.clone())
+                                .unwrap_or_default();
+                            files.push(XarFile {
+                                id,
+                                name: String::new(),
+                                data: None,
+                            });
+                            parents.push(files.len() - 1);
+                            elements.clear();
+                        },
+                        "data" if !parents.is_empty() && elements.is_empty() => {
+                            data = Some(XarData {
+                                offset: 0,
+                                length: 0,
+                                size: 0,
+                                encoding: XarEncoding::None,
+                            });
+                            encoding = XarEncoding::None;
+                        },
+                        "encoding" if data.is_some() => {
+                            if let Some(attr) = attributes.iter().find(|attr| attr.name.local_name == "style") {
+                                encoding = XarEncoding::from_style(attr.value.as_str())?;
+                            }
+                        },
+                        _ => {},
+                    }
+                    if name.local_name != "file" {
+                        elements.push(name.local_name);
+                    }
+                },
+                XmlEvent::Characters(text) => {
+                    let index = match parents.last() {
+                        Some(index) => *index,
+                        None => continue,
+                    };
+                    let path: Vec<&str> = elements.iter().map(|s| s.as_str()).collect();
+                    match path.as_slice() {
+                        ["name"] => {
+                            let parent = if parents.len() > 1 {
+                                Some(files[parents[parents.len() - 2]].name.clone())
+                            } else {
+                                None
+                            };
+                            files[index].name = match parent {
+                                Some(parent) => format!("{}/{}", parent, text),
+                                None => text,
+                            };
+                        },
+                        ["data", field] if *field == "offset" || *field == "length" || *field == "size" => {
+                            if let Some(ref mut data) = data {
+                                let value = text.trim().parse::<u64>()
+                                    .map_err(|_| RecompileError::MalformedXar(format!("bad xar data {} \"{}\"", field, text)))?;
+                                match *field {
+                                    "offset" => data.offset = value,
+                                    "length" => data.length = value,
+                                    "size" => data.size = value,
+                                    _ => {},
+                                }
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+                XmlEvent::EndElement { name } => {
+                    if name.local_name == "file" {
+                        parents.pop();
+                        elements.clear();
+                    } else {
+                        elements.pop();
+                        if name.local_name == "data" && elements.is_empty() {
+                            if let (Some(index), Some(mut data)) = (parents.last(), data.take()) {
+                                data.encoding = encoding;
+                                files[*index].data = Some(data);
+                            }
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(files)
+    }
+
+    pub fn find(&self, name: &str) -> Option<&XarFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    /// Decode the contents of `file` from the heap.
+    pub fn extract(&self, file: &XarFile) -> Result<Vec<u8>, Error> {
+        let data = match file.data {
+            Some(ref data) => data,
+            None => bail!(RecompileError::MalformedXar(format!("xar entry {} has no data", file.name))),
+        };
+
+        let start = data.offset as usize;
+        let raw = start.checked_add(data.length as usize)
+            .filter(|end| *end <= self.heap.len())
+            .map(|end| &self.heap[start..end])
+            .ok_or_else(|| RecompileError::MalformedXar(format!("xar entry {} exceeds the heap", file.name)))?;
+
+        let mut out = Vec::new();
+        match data.encoding {
+            XarEncoding::None => out.extend_from_slice(raw),
+            XarEncoding::Gzip => {
+                ZlibDecoder::new(raw).read_to_end(&mut out)
+                    .map_err(|e| RecompileError::MalformedXar(format!("xar entry {}, {}", file.name, e)))?;
+            },
+            XarEncoding::Bzip2 => {
+                BzDecoder::new(raw).read_to_end(&mut out)
+                    .map_err(|e| RecompileError::MalformedXar(format!("xar entry {}, {}", file.name, e)))?;
+            },
+        }
+
+        if out.len() as u64 != data.size {
+            bail!(RecompileError::MalformedXar(format!("xar entry {} size mismatch, expect {} got {}", file.name, data.size, out.len())));
+        }
+
+        Ok(out)
+    }
+
+    /// `name` below `dir`, refusing absolute names and `..` which would escape it.
+    fn entry_path(dir: &Path, name: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(name);
+        if relative.components().any(|component| match component {
+            Component::Normal(_) | Component::CurDir => false,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => true,
+        }) {
+            bail!(RecompileError::MalformedXar(format!("xar entry {} escapes the archive", name)));
+        }
+        Ok(dir.join(relative))
+    }
+
+    /// Write every file with data into `dir`, like `xar -xf`.
+    pub fn extract_all(&self, dir: &Path) -> Result<(), Error> {
+        for file in &self.files {
+            let path = Self::entry_path(dir, &file.name)?;
+            if file.data.is_none() {
+                fs::create_dir_all(&path)?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, self.extract(file)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate byteorder;
+    extern crate bzip2;
+    extern crate flate2;
+
+    use self::byteorder::{BigEndian, WriteBytesExt};
+    use self::flate2::write::ZlibEncoder;
+    use self::flate2::Compression;
+    use super::*;
+
+    use self::bzip2::write::BzEncoder;
+
+    use std::env;
+    use std::io::Write;
+
+    /// A xar archive of `toc` and `heap`, with a sha1 checksum algorithm that is never checked.
+    fn archive(toc: &str, heap: &[u8]) -> Vec<u8> {
+        let compressed = zlib(toc.as_bytes());
+
+        let mut w = Vec::new();
+        w.write_u32::<BigEndian>(XAR_MAGIC).unwrap();
+        w.write_u16::<BigEndian>(XAR_HEADER_SIZE).unwrap();
+        w.write_u16::<BigEndian>(1).unwrap();
+        w.write_u64::<BigEndian>(compressed.len() as u64).unwrap();
+        w.write_u64::<BigEndian>(toc.len() as u64).unwrap();
+        w.write_u32::<BigEndian>(1).unwrap();
+        w.extend_from_slice(&compressed);
+        w.extend_from_slice(heap);
+        w
+    }
+
+    /// A TOC of a single stored file `name` holding `size` bytes at the start of the heap.
+    fn single_file_toc(name: &str, size: usize) -> String {
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <xar><toc><file id=\"1\"><name>{}</name>\
+                 <data><offset>0</offset><length>{}</length><size>{}</size>\
+                 <encoding style=\"application/octet-stream\"/></data>\
+                 </file></toc></xar>", name, size, size)
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(Vec::new(), self::bzip2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn header() {
+        let payload = archive(&single_file_toc("a", 0), b"");
+        let header = XarHeader::parse(&payload).unwrap();
+        assert_eq!(header.magic, XAR_MAGIC);
+        assert_eq!(header.size, XAR_HEADER_SIZE);
+        assert_eq!(header.version, 1);
+        assert_eq!(header.cksum_alg, 1);
+
+        assert!(XarHeader::parse(&payload[..XAR_HEADER_SIZE as usize - 1]).is_err());
+        let mut bad_magic = payload.clone();
+        bad_magic[0] = b'X';
+        assert!(XarHeader::parse(&bad_magic).is_err());
+        let mut small = payload.clone();
+        small[5] = 8;
+        assert!(XarHeader::parse(&small).is_err());
+    }
+
+    #[test]
+    fn malformed_toc_lengths() {
+        let payload = archive(&single_file_toc("a", 0), b"");
+        for offset in &[8, 16] {
+            let mut bad = payload.clone();
+            bad[*offset..*offset + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+            assert!(XarArchive::parse(&bad).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_data_size() {
+        let toc = single_file_toc("a", 4).replace("<size>4</size>", &format!("<size>{}</size>", u64::MAX));
+        let payload = archive(&toc, b"data");
+        let xar = XarArchive::parse(&payload).unwrap();
+        assert!(xar.extract(&xar.files[0]).is_err());
+        let toc = single_file_toc("a", 4).replace("<length>4</length>", &format!("<length>{}</length>", u64::MAX));
+        let payload = archive(&toc, b"data");
+        let xar = XarArchive::parse(&payload).unwrap();
+        assert!(xar.extract(&xar.files[0]).is_err());
+    }
+
+    #[test]
+    fn toc_exceeding_the_archive() {
+        let payload = archive(&single_file_toc("a", 0), b"");
+        assert!(XarArchive::parse(&payload[..payload.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn nested_files() {
+        let toc = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                   <xar><toc><creation-time>2018-01-01T00:00:00</creation-time>\
+                   <file id=\"1\"><name>dir</name><type>directory</type>\
+                   <file id=\"2\"><name>a</name>\
+                   <data><offset>0</offset><length>1</length><size>1</size>\
+                   <encoding style=\"application/octet-stream\"/></data></file>\
+                   </file>\
+                   <file id=\"3\"><name>b</name></file>\
+                   </toc></xar>";
+        let payload = archive(toc, b"x");
+        let xar = XarArchive::parse(&payload).unwrap();
+        let names = xar.files.iter().map(|file| (file.id.as_str(), file.name.as_str())).collect::<Vec<_>>();
+        assert_eq!(names, vec![("1", "dir"), ("2", "dir/a"), ("3", "b")]);
+        assert!(xar.files[0].data.is_none());
+        assert_eq!(xar.extract(xar.find("dir/a").unwrap()).unwrap(), b"x");
+        assert!(xar.extract(xar.find("b").unwrap()).is_err());
+    }
+
+    #[test]
+    fn encodings() {
+        let contents = b"the contents of every encoding";
+        let stored = contents.to_vec();
+        let gzip = zlib(contents);
+        let bzip2 = bzip2(contents);
+
+        let mut toc = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><xar><toc>");
+        let mut heap = Vec::new();
+        for (name, style, data) in &[("none", ENCODING_NONE, &stored), ("gzip", ENCODING_GZIP, &gzip), ("bzip2", ENCODING_BZIP2, &bzip2)] {
+            toc.push_str(&format!("<file id=\"{}\"><name>{}</name><data><offset>{}</offset><length>{}</length><size>{}</size>\
+                                   <encoding style=\"{}\"/></data></file>",
+                                  name, name, heap.len(), data.len(), contents.len(), style));
+            heap.extend_from_slice(data);
+        }
+        toc.push_str("</toc></xar>");
+
+        let payload = archive(&toc, &heap);
+        let xar = XarArchive::parse(&payload).unwrap();
+        let encodings = xar.files.iter().map(|file| file.data.as_ref().unwrap().encoding).collect::<Vec<_>>();
+        assert_eq!(encodings, vec![XarEncoding::None, XarEncoding::Gzip, XarEncoding::Bzip2]);
+        for file in &xar.files {
+            assert_eq!(xar.extract(file).unwrap(), &contents[..], "{}", file.name);
+        }
+
+        assert!(XarEncoding::from_style("application/x-lzma").is_err());
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let toc = single_file_toc("a", 4).replace("<size>4</size>", "<size>5</size>");
+        let payload = archive(&toc, b"data");
+        let xar = XarArchive::parse(&payload).unwrap();
+        assert!(xar.extract(&xar.files[0]).is_err());
+    }
+
+    #[test]
+    fn extract_refuses_escaping_names() {
+        let dir = env::temp_dir().join(format!("BitcodeRecompiler-xar-{}", std::process::id()));
+        for name in &["../escape", "a/../../escape", "/tmp/escape"] {
+            let payload = archive(&single_file_toc(name, 4), b"data");
+            let xar = XarArchive::parse(&payload).unwrap();
+            let err = xar.extract_all(&dir).unwrap_err();
+            assert!(err.to_string().contains("escapes the archive"), "{}: {}", name, err);
+        }
+        assert!(!dir.join("..").join("escape").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,199 @@
+//! Recompile a universal static library whose slices are archives of bitcode objects.
+//!
+//! The compiler is a shell script writing a prebuilt object, outputs are written to the
+//! current directory, so everything runs in one test.
+
+#![cfg(unix)]
+
+extern crate bitcode_recompiler;
+extern crate byteorder;
+extern crate mach_object;
+
+use bitcode_recompiler::archive::{self, ArMember};
+use bitcode_recompiler::context::FileContext;
+use bitcode_recompiler::{MachOProcess, ToolchainOptions};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use mach_object::OFile;
+
+use std::env;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+const CPU_SUBTYPE_X86_64_ALL: u32 = 3;
+const CPU_TYPE_ARM64: u32 = 0x0100_000c;
+const CPU_SUBTYPE_ARM64_ALL: u32 = 0;
+
+/// `clang -cc1 ... -o output` copying `object` to `output`.
+fn fake_clang(object: &Path) -> String {
+    format!("#!/bin/sh\n\
+             prev=\"\"\n\
+             for arg in \"$@\"; do\n\
+               [ \"$prev\" = \"-o\" ] && output=$arg\n\
+               prev=$arg\n\
+             done\n\
+             cp {} \"$output\"\n", object.display())
+}
+
+fn name16(name: &str) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    bytes
+}
+
+/// An `MH_OBJECT` whose only section is `__LLVM,__bitcode` holding `module`.
+fn bitcode_object(cputype: u32, cpusubtype: u32, module: &[u8]) -> Vec<u8> {
+    let data_offset = 32 + 72 + 80;
+    let mut w = Vec::new();
+    w.write_u32::<LittleEndian>(0xfeed_facf).unwrap();
+    w.write_u32::<LittleEndian>(cputype).unwrap();
+    w.write_u32::<LittleEndian>(cpusubtype).unwrap();
+    w.write_u32::<LittleEndian>(1).unwrap(); // MH_OBJECT
+    w.write_u32::<LittleEndian>(1).unwrap();
+    w.write_u32::<LittleEndian>(72 + 80).unwrap();
+    w.write_u32::<LittleEndian>(0).unwrap();
+    w.write_u32::<LittleEndian>(0).unwrap();
+
+    w.write_u32::<LittleEndian>(0x19).unwrap(); // LC_SEGMENT_64
+    w.write_u32::<LittleEndian>(72 + 80).unwrap();
+    w.write_all(&name16("")).unwrap();
+    w.write_u64::<LittleEndian>(0).unwrap();
+    w.write_u64::<LittleEndian>(module.len() as u64).unwrap();
+    w.write_u64::<LittleEndian>(data_offset as u64).unwrap();
+    w.write_u64::<LittleEndian>(module.len() as u64).unwrap();
+    w.write_u32::<LittleEndian>(7).unwrap();
+    w.write_u32::<LittleEndian>(7).unwrap();
+    w.write_u32::<LittleEndian>(1).unwrap();
+    w.write_u32::<LittleEndian>(0).unwrap();
+
+    w.write_all(&name16("__bitcode")).unwrap();
+    w.write_all(&name16("__LLVM")).unwrap();
+    w.write_u64::<LittleEndian>(0).unwrap();
+    w.write_u64::<LittleEndian>(module.len() as u64).unwrap();
+    w.write_u32::<LittleEndian>(data_offset).unwrap();
+    for _ in 0..7 {
+        w.write_u32::<LittleEndian>(0).unwrap();
+    }
+
+    w.write_all(module).unwrap();
+    w
+}
+
+fn static_library(dir: &Path, cputype: u32, cpusubtype: u32) -> Vec<u8> {
+    let object = bitcode_object(cputype, cpusubtype, b"BC\xc0\xde module");
+    let member = ArMember {
+        name: "foo.o".to_string(),
+        date: 0,
+        uid: 0,
+        gid: 0,
+        mode: 0o644,
+        data: &object,
+    };
+    let path = dir.join(format!("slice.{}", cputype));
+    archive::write_archive(&[member], &path).unwrap();
+    fs::read(&path).unwrap()
+}
+
+/// A universal file of `slices`, 4 KiB aligned.
+fn universal(slices: &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+    let mut w = Vec::new();
+    w.write_u32::<BigEndian>(0xcafe_babe).unwrap();
+    w.write_u32::<BigEndian>(slices.len() as u32).unwrap();
+    let mut offset = 0x1000;
+    for &(cputype, cpusubtype, ref data) in slices {
+        w.write_u32::<BigEndian>(cputype).unwrap();
+        w.write_u32::<BigEndian>(cpusubtype).unwrap();
+        w.write_u32::<BigEndian>(offset).unwrap();
+        w.write_u32::<BigEndian>(data.len() as u32).unwrap();
+        w.write_u32::<BigEndian>(12).unwrap();
+        offset = (offset + data.len() as u32).div_ceil(0x1000) * 0x1000;
+    }
+    for (_, _, data) in slices {
+        w.resize(w.len().div_ceil(0x1000) * 0x1000, 0);
+        w.write_all(data).unwrap();
+    }
+    w
+}
+
+/// `(arch, path)` of the slices written.
+fn recompile(input: &Path, work_dir: &Path, clang: &Path, universal: bool) -> Vec<(String, String)> {
+    let payload = fs::read(input).unwrap();
+    let ofile = OFile::parse(&mut Cursor::new(&payload[..])).unwrap();
+    let toolchain = ToolchainOptions {
+        clang: Some(clang.to_string_lossy().into_owned()),
+        ..ToolchainOptions::default()
+    };
+
+    let mut process = MachOProcess::new();
+    process.set_input(&input.to_string_lossy());
+    process.set_universal(universal);
+    process.set_work_dir(work_dir);
+    process.handle_ofile(&ofile, &mut FileContext::new(&payload), &toolchain).unwrap();
+    process.slices().to_vec()
+}
+
+fn slice_kinds(path: &Path) -> Vec<bool> {
+    let payload = fs::read(path).unwrap();
+    match OFile::parse(&mut Cursor::new(&payload[..])).unwrap() {
+        OFile::FatFile { files, .. } => files.iter().map(|(_, file)| matches!(*file, OFile::ArFile { .. })).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[test]
+fn fat_archive_slices() {
+    let root = env::temp_dir().join(format!("BitcodeRecompiler-test-{}", std::process::id()));
+    let input_dir = root.join("in");
+    let output_dir = root.join("out");
+    let work_dir = root.join("work");
+    for dir in &[&input_dir, &output_dir, &work_dir] {
+        fs::create_dir_all(dir).unwrap();
+    }
+
+    let object = root.join("compiled.o");
+    fs::write(&object, bitcode_object(CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL, b"BC\xc0\xde compiled")).unwrap();
+    let clang = root.join("clang");
+    fs::write(&clang, fake_clang(&object)).unwrap();
+    fs::set_permissions(&clang, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let input = input_dir.join("libfoo.a");
+    let slices = vec![
+        (CPU_TYPE_X86_64, CPU_SUBTYPE_X86_64_ALL, static_library(&root, CPU_TYPE_X86_64, CPU_SUBTYPE_X86_64_ALL)),
+        (CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL, static_library(&root, CPU_TYPE_ARM64, CPU_SUBTYPE_ARM64_ALL)),
+    ];
+    fs::write(&input, universal(&slices)).unwrap();
+    assert_eq!(slice_kinds(&input), vec![true, true]);
+
+    env::set_current_dir(&output_dir).unwrap();
+
+    // every slice is an archive of its own
+    let slices = recompile(&input, &work_dir, &clang, false);
+    assert_eq!(slices, vec![
+        ("x86_64".to_string(), "./libfoo.a.x86_64".to_string()),
+        ("arm64".to_string(), "./libfoo.a.arm64".to_string()),
+    ]);
+    for arch in &["x86_64", "arm64"] {
+        let slice: PathBuf = output_dir.join(format!("libfoo.a.{}", arch));
+        assert!(fs::read(&slice).unwrap().starts_with(b"!<arch>\n"), "{} is not an archive", slice.display());
+    }
+
+    // the slices merged back into one universal archive
+    recompile(&input, &work_dir, &clang, true);
+    assert_eq!(slice_kinds(&output_dir.join("libfoo.a")), vec![true, true]);
+
+    // run next to the input, which must be left alone
+    let original = fs::read(&input).unwrap();
+    env::set_current_dir(&input_dir).unwrap();
+    recompile(&input, &work_dir, &clang, true);
+    assert_eq!(fs::read(&input).unwrap(), original);
+    assert_eq!(slice_kinds(&input_dir.join("libfoo.recompiled.a")), vec![true, true]);
+    // the slices stay in the work directory
+    for arch in &["x86_64", "arm64"] {
+        assert!(!input_dir.join(format!("libfoo.recompiled.a.{}", arch)).exists());
+        assert!(work_dir.join(arch).join(format!("libfoo.recompiled.a.{}", arch)).is_file());
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}